@@ -0,0 +1,64 @@
+use super::catalog::CatalogSource;
+use super::sql::*;
+use crate::{Configuration, Telemetry};
+use log::{debug, info, warn};
+
+fn create_async_action_request(source: &str) -> SQLRequest {
+    let query = |sql: &str| RunSQLQuery {
+        request_type: "run_sql".to_string(),
+        args: RunSQLArgs {
+            source: source.to_string(),
+            cascade: false,
+            read_only: true,
+            sql: sql.to_string(),
+        },
+    };
+    SQLRequest {
+        request_type: "bulk".to_string(),
+        args: vec![
+            query("SELECT action_name, status, COUNT(*) AS value FROM hdb_catalog.hdb_action_log GROUP BY action_name, status;"),
+            query(&oldest_pending_age_sql("run_sql", "hdb_catalog.hdb_action_log", "created_at", "status IN ('created', 'processing')", Some("action_name"))),
+        ],
+    }
+}
+
+async fn collect_async_actions(cfg: &Configuration, metric_obj: &Telemetry, source: &str) -> Result<(), String> {
+    let response = make_sql_request(&create_async_action_request(source), cfg).await.map_err(|e| e.to_string())?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(format!("invalid status code: {}", response.status()));
+    }
+    let mut results = response.json::<Vec<SQLRowsResult>>().await.map_err(|e| e.to_string())?.into_iter();
+    let mut next = |labels: &[&str]| match results.next() {
+        Some(result) => result.samples("value", labels),
+        None => Err("missing result".to_string()),
+    };
+
+    // Index values must match create_async_action_request() for coherence
+    let counts = next(&["action_name", "status"])?;
+    let ages = next(&["action_name"])?;
+    metric_obj.ASYNC_ACTIONS.set(counts);
+    metric_obj.ASYNC_ACTIONS_OLDEST_PENDING_AGE.set(ages);
+    Ok(())
+}
+
+pub(crate) async fn check_async_actions(cfg: &Configuration, metric_obj: &Telemetry, catalog: Option<&CatalogSource>) {
+    if cfg.disabled_collectors.contains(&crate::Collectors::AsyncActions) {
+        info!("Not collecting async actions.");
+        return;
+    }
+    let source = match catalog {
+        Some(CatalogSource::Sql(source)) => source.as_str(),
+        Some(CatalogSource::MetadataApi) => {
+            debug!("Not collecting async actions, the metadata database is not a source");
+            return;
+        }
+        // The metadata database source could not be resolved yet
+        None => return,
+    };
+
+    debug!("Running SQL query for async actions");
+    if let Err(e) = collect_async_actions(cfg, metric_obj, source).await {
+        metric_obj.ERRORS_TOTAL.with_label_values(&["actions"]).inc();
+        warn!("Failed to collect async actions check {}", e);
+    }
+}
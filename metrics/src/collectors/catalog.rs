@@ -0,0 +1,119 @@
+use super::metadata::MetadataExportRequest;
+use super::sql::*;
+use crate::{Collectors, Configuration, Telemetry};
+use log::{debug, info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+/// Where the cron triggers, one off events and async actions, which are kept in the
+/// metadata database, are read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CatalogSource {
+    /// The postgres source of the metadata database, queried with SQL
+    Sql(String),
+    /// No source exposes the metadata database, the metadata API is used instead
+    MetadataApi,
+}
+
+/// The configured source, otherwise the first postgres source with the cron and
+/// scheduled event tables, which only exist in the metadata database. `None` if the
+/// sources could not be checked.
+pub(crate) async fn resolve_catalog_source(cfg: &Configuration, metric_obj: &Telemetry) -> Option<CatalogSource> {
+    if let Some(source) = &cfg.metadata_source {
+        return Some(CatalogSource::Sql(source.clone()));
+    }
+    let catalog_collectors = [Collectors::CronTriggers, Collectors::ScheduledEvents, Collectors::AsyncActions];
+    if catalog_collectors.iter().all(|collector| cfg.disabled_collectors.contains(collector)) {
+        return None;
+    }
+
+    let metadata = match metadata_request::<Map<String, Value>>(cfg, &MetadataExportRequest::export_metadata()).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            warn!("Failed to discover the metadata database source {}", e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["metadata"]).inc();
+            return None;
+        }
+    };
+    let mut sources: Vec<&str> = metadata.get("metadata").and_then(|metadata| metadata["sources"].as_array()).into_iter().flatten()
+        .filter(|source| source["kind"].as_str() == Some("postgres"))
+        .filter_map(|source| source["name"].as_str())
+        .collect();
+    // The metadata database is usually also the default source
+    sources.sort_by_key(|name| *name != "default");
+
+    for source in sources {
+        debug!("Checking source {} for the metadata database", source);
+        match has_catalog_tables(cfg, source).await {
+            Ok(true) => {
+                info!("Reading cron triggers and one off events from source {}", source);
+                return Some(CatalogSource::Sql(source.to_string()));
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Failed to check source {} for the metadata database {}", source, e);
+                metric_obj.ERRORS_TOTAL.with_label_values(&["metadata"]).inc();
+                return None;
+            }
+        }
+    }
+    info!("The metadata database is not a source, reading cron triggers and one off events from the metadata API");
+    Some(CatalogSource::MetadataApi)
+}
+
+async fn has_catalog_tables(cfg: &Configuration, source: &str) -> Result<bool, String> {
+    let request = SQLRequest {
+        request_type: "bulk".to_string(),
+        args: vec![RunSQLQuery {
+            request_type: "run_sql".to_string(),
+            args: RunSQLArgs {
+                source: source.to_string(),
+                cascade: false,
+                read_only: true,
+                sql: "SELECT (to_regclass('hdb_catalog.hdb_cron_events') IS NOT NULL AND to_regclass('hdb_catalog.hdb_scheduled_events') IS NOT NULL)::int AS value;".to_string(),
+            },
+        }],
+    };
+    let response = make_sql_request(&request, cfg).await.map_err(|e| e.to_string())?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(format!("invalid status code: {}", response.status()));
+    }
+    let results = response.json::<Vec<SQLRowsResult>>().await.map_err(|e| e.to_string())?;
+    let samples = results.into_iter().next().ok_or("empty response")?.samples("value", &[])?;
+    Ok(samples.first().map(|(_, value)| *value == 1.0).unwrap_or_default())
+}
+
+pub(crate) async fn metadata_request<T: DeserializeOwned>(cfg: &Configuration, request: &impl serde::Serialize) -> Result<T, String> {
+    let admin_secret = cfg.hasura_admin.as_deref().ok_or("admin secret missing")?;
+    let response = reqwest::Client::new()
+        .post(format!("{}/v1/metadata", cfg.hasura_addr))
+        .json(request)
+        .header("x-hasura-admin-secret", admin_secret)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(format!("invalid status code: {}", response.status()));
+    }
+    response.json::<T>().await.map_err(|e| e.to_string())
+}
+
+#[derive(Deserialize)]
+struct EventsCount {
+    count: i64,
+}
+
+/// Counts the events per status via `get_scheduled_events`, `args` selects the
+/// events, e.g. `{"type": "one_off"}`.
+pub(crate) async fn count_scheduled_events(cfg: &Configuration, args: Value, statuses: &[&[&str]]) -> Result<Vec<i64>, String> {
+    let requests: Vec<Value> = statuses.iter().map(|status| {
+        let mut args = args.clone();
+        args["status"] = json!(status);
+        args["limit"] = json!(1);
+        args["get_rows_count"] = json!(true);
+        json!({ "type": "get_scheduled_events", "args": args })
+    }).collect();
+    let counts = metadata_request::<Vec<EventsCount>>(cfg, &json!({ "type": "bulk", "args": requests })).await?;
+    Ok(counts.into_iter().map(|c| c.count).collect())
+}
@@ -2,80 +2,112 @@ use super::sql::*;
 use crate::{Configuration, Telemetry};
 use log::{warn, info, debug};
 use crate::telemetry::MetricOption;
+use super::invocations::{check_invocations, InvocationKind};
+use super::incremental::{EventTable, HighWaterMarks};
+use super::catalog::{count_scheduled_events, metadata_request, CatalogSource};
+use serde::Deserialize;
+use serde_json::json;
 
 
-fn create_cron_trigger_request() -> SQLRequest {
-    SQLRequest {
-            request_type: "bulk".to_string(),
-            args: vec![
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'error' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'delivered' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'scheduled' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'error' or status = 'delivered' GROUP BY trigger_name;".to_string()
-                    }
-                },
-            ],
+/// The queries of the bulk request.
+#[derive(Clone, Copy)]
+enum CronTriggerQuery {
+    Failed,
+    Successful,
+    Pending,
+    Processed,
+    OldestPendingAge,
+}
+
+impl BulkQuery for CronTriggerQuery {
+    const ALL: &'static [Self] = &[Self::Failed, Self::Successful, Self::Pending, Self::Processed, Self::OldestPendingAge];
+
+    fn counts_settled(self) -> bool {
+        matches!(self, Self::Failed | Self::Successful | Self::Processed)
+    }
+
+    fn sql(self, request_type: &str) -> String {
+        match self {
+            Self::Failed => "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'error' GROUP BY trigger_name;".to_string(),
+            Self::Successful => "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'delivered' GROUP BY trigger_name;".to_string(),
+            Self::Pending => "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'scheduled' GROUP BY trigger_name;".to_string(),
+            Self::Processed => "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'error' or status = 'delivered' GROUP BY trigger_name;".to_string(),
+            // Only overdue events are late, the others wait for their scheduled time
+            Self::OldestPendingAge => oldest_pending_age_sql(request_type, "hdb_catalog.hdb_cron_events", "scheduled_time", "status = 'scheduled' AND scheduled_time < NOW()", Some("trigger_name")),
         }
+    }
 }
 
-pub(crate) async fn check_cron_triggers(cfg: &Configuration, metric_obj: &Telemetry) {
+#[derive(Deserialize)]
+struct CronTriggers {
+    cron_triggers: Vec<CronTrigger>,
+}
+
+#[derive(Deserialize)]
+struct CronTrigger {
+    name: String,
+}
+
+/// Counts the events per trigger with the metadata API, without the age and invocations
+/// only available with SQL.
+async fn check_cron_triggers_from_metadata(cfg: &Configuration, metric_obj: &Telemetry) -> Result<(), String> {
+    let triggers = metadata_request::<CronTriggers>(cfg, &json!({ "type": "get_cron_triggers", "args": {} })).await?;
+    for trigger in triggers.cron_triggers {
+        let args = json!({ "type": "cron", "trigger_name": trigger.name });
+        // Same order as CronTriggerQuery
+        let counts = count_scheduled_events(cfg, args, &[&["error"], &["delivered"], &["scheduled"], &["error", "delivered"]]).await?;
+        let metrics = [&metric_obj.CRON_TRIGGER_FAILED, &metric_obj.CRON_TRIGGER_SUCCESSFUL, &metric_obj.CRON_TRIGGER_PENDING, &metric_obj.CRON_TRIGGER_PROCESSED];
+        for (metric, count) in metrics.iter().zip(counts) {
+            metric.with_label_values(&[trigger.name.as_str()]).set(count);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn check_cron_triggers(cfg: &Configuration, metric_obj: &Telemetry, catalog: Option<&CatalogSource>, marks: &HighWaterMarks) {
     if cfg.disabled_collectors.contains(&crate::Collectors::CronTriggers) {
         info!("Not collecting cron triggers.");
         return;
     }
+    let source = match catalog {
+        Some(CatalogSource::Sql(source)) => source.as_str(),
+        Some(CatalogSource::MetadataApi) => {
+            debug!("Running metadata API queries for cron triggers");
+            if let Err(e) = check_cron_triggers_from_metadata(cfg, metric_obj).await {
+                metric_obj.ERRORS_TOTAL.with_label_values(&["cron"]).inc();
+                warn!("Failed to collect cron triggers check {}", e);
+            }
+            return;
+        }
+        // The metadata database source could not be resolved yet
+        None => return,
+    };
     debug!("Running SQL query for cron triggers");
-    let sql_result = make_sql_request(&create_cron_trigger_request(), cfg).await;
+    let queries = bulk_queries::<CronTriggerQuery>(cfg.incremental_counts);
+    let sql_result = make_sql_request(&bulk_request("run_sql", source, &queries), cfg).await;
     match sql_result {
         Ok(v) => {
             if v.status() == reqwest::StatusCode::OK {
                 let response = v.json::<Vec<SQLResult>>().await;
                 match response {
                     Ok(v) => {
-                        v.iter().enumerate().for_each(|(index, query)| {
-
-                            let obj = match index as i32 {
-                                // Index values must match create_cron_trigger_request() for coherence
-                                0 => Ok((MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_FAILED), "failed cron triggers")),
-                                1 => Ok((MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_SUCCESSFUL),"successful cron triggers")),
-                                2 => Ok((MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_PENDING),"pending cron triggers")),
-                                3 => Ok((MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_PROCESSED),"processed cron triggers")),
-                                _ => {
-                                    warn!("Unexpected entry {:?}",query);
-                                    Err(format!("Unexpected entry {:?}",query))
+                        queries.iter().zip(v.iter()).for_each(|(query, result)| {
+                            let obj = match query {
+                                CronTriggerQuery::Failed => (MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_FAILED), "failed cron triggers"),
+                                CronTriggerQuery::Successful => (MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_SUCCESSFUL), "successful cron triggers"),
+                                CronTriggerQuery::Pending => (MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_PENDING), "pending cron triggers"),
+                                CronTriggerQuery::Processed => (MetricOption::IntGaugeVec(&metric_obj.CRON_TRIGGER_PROCESSED), "processed cron triggers"),
+                                CronTriggerQuery::OldestPendingAge => {
+                                    // Replaced at once, so triggers without overdue events disappear
+                                    match result.samples(None) {
+                                        Some(ages) => metric_obj.CRON_TRIGGER_OLDEST_PENDING_AGE.set(ages),
+                                        None => info!("Result of SQL query for 'oldest overdue cron trigger age' has failed or is empty: {:?}", result),
+                                    }
+                                    return;
                                 }
                             };
 
-                            process_sql_result(query,obj,None);
+                            process_sql_result(result, obj, None);
                         });
                     }
                     Err(e) => {
@@ -93,4 +125,35 @@ pub(crate) async fn check_cron_triggers(cfg: &Configuration, metric_obj: &Teleme
             warn!("Failed to collect cron triggers check {}", e);
         }
     };
+
+    if cfg.incremental_counts {
+        debug!("Running SQL query for settled cron trigger events");
+        match marks.settled_events(cfg, EventTable::CronEvents, source).await {
+            Ok(settled) => {
+                for (trigger_name, count) in settled.delivered {
+                    metric_obj.CRON_TRIGGER_SUCCESSFUL_TOTAL.with_label_values(&[trigger_name.as_str()]).inc_by(count);
+                }
+                for (trigger_name, count) in settled.failed {
+                    metric_obj.CRON_TRIGGER_FAILED_TOTAL.with_label_values(&[trigger_name.as_str()]).inc_by(count);
+                }
+            }
+            Err(e) => {
+                metric_obj.ERRORS_TOTAL.with_label_values(&["cron"]).inc();
+                warn!("Failed to collect settled cron trigger events {}", e);
+            }
+        }
+    }
+
+    debug!("Running SQL query for cron trigger invocations");
+    match check_invocations(cfg, source, InvocationKind::CronTriggers, metric_obj.CRON_TRIGGER_DELIVERY_LATENCY.buckets()).await {
+        Ok(stats) => {
+            metric_obj.CRON_TRIGGER_DELIVERY_LATENCY.set(stats.latency);
+            metric_obj.CRON_TRIGGER_INVOCATIONS.set(stats.invocations);
+            metric_obj.CRON_TRIGGER_RETRIES.set(stats.retries);
+        }
+        Err(e) => {
+            metric_obj.ERRORS_TOTAL.with_label_values(&["cron"]).inc();
+            warn!("Failed to collect cron trigger invocations {}", e);
+        }
+    }
 }
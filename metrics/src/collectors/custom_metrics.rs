@@ -0,0 +1,261 @@
+use futures::future::join_all;
+use log::{debug, info, warn};
+use prometheus::proto::MetricType;
+use prometheus::Opts;
+use serde::Deserialize;
+use tokio::{sync::watch, time};
+
+use super::graphql_metrics::{self, GraphQLMetricConfig};
+use super::sql::*;
+use crate::telemetry::{Sample, SnapshotVec};
+use crate::{Configuration, Telemetry};
+
+#[derive(Deserialize)]
+struct CustomMetricsFile {
+    #[serde(default)]
+    metrics: Vec<CustomMetricConfig>,
+    #[serde(default)]
+    graphql_metrics: Vec<GraphQLMetricConfig>,
+}
+
+impl CustomMetricsFile {
+    /// Parses the file as TOML if its extension is `.toml`, as YAML otherwise.
+    fn parse(path: &str, contents: &str) -> Result<Self, String> {
+        match std::path::Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(contents).map_err(|e| e.to_string()),
+            _ => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CustomMetricType {
+    #[default]
+    Gauge,
+    Counter,
+}
+
+/// A metric published from the rows of a read-only SQL query.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CustomMetricConfig {
+    pub name: String,
+    pub help: String,
+    /// The Hasura source the query runs on
+    #[serde(default = "default_source")]
+    pub source: String,
+    /// The kind of the source, e.g. `postgres` or `mssql`
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    pub sql: String,
+    /// The column holding the value of the metric
+    pub value: String,
+    /// The columns holding the label values
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(rename = "type", default)]
+    pub metric_type: CustomMetricType,
+    /// Milliseconds between the queries, the collect interval by default
+    pub interval: Option<u64>,
+}
+
+impl CustomMetricConfig {
+    /// Checks the interval, a zero interval would never wait between the queries.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.interval {
+            Some(0) => Err("interval must be at least 1 millisecond".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn default_source() -> String {
+    "default".to_string()
+}
+
+fn default_kind() -> String {
+    "postgres".to_string()
+}
+
+/// Registers the metric, its samples are replaced by every successful query.
+fn register(name: &str, help: &str, label_names: &[&str], metric_type: CustomMetricType, metric_obj: &Telemetry) -> std::io::Result<SnapshotVec> {
+    let opts = Opts::new(name, help).const_labels(metric_obj.common_labels.clone());
+    let metric_type = match metric_type {
+        CustomMetricType::Gauge => MetricType::GAUGE,
+        CustomMetricType::Counter => MetricType::COUNTER,
+    };
+    SnapshotVec::new(opts, label_names, metric_type)
+        .and_then(|metric| metric_obj.registry.register(Box::new(metric.clone())).map(|_| metric))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid custom metric {}: {}", name, e)))
+}
+
+/// The metrics declared in the `--custom-metrics-file`.
+pub(crate) struct CustomMetrics {
+    metrics: Vec<(CustomMetricConfig, SnapshotVec)>,
+    graphql_metrics: Vec<(GraphQLMetricConfig, SnapshotVec)>,
+}
+
+impl CustomMetrics {
+    /// Reads the metrics from the file and registers them.
+    pub(crate) fn load(cfg: &Configuration, metric_obj: &Telemetry) -> std::io::Result<Self> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        let path = match &cfg.custom_metrics_file {
+            Some(path) => path,
+            None => return Ok(CustomMetrics { metrics: vec![], graphql_metrics: vec![] }),
+        };
+        let file = CustomMetricsFile::parse(path, &std::fs::read_to_string(path)?)
+            .map_err(|e| invalid(format!("Invalid custom metrics file {}: {}", path, e)))?;
+
+        let mut metrics = Vec::new();
+        for config in file.metrics {
+            config.validate().map_err(|e| invalid(format!("Invalid custom metric {}: {}", config.name, e)))?;
+            let labels: Vec<&str> = config.labels.iter().map(String::as_str).collect();
+            let metric = register(&config.name, &config.help, &labels, config.metric_type, metric_obj)?;
+            metrics.push((config, metric));
+        }
+        let mut graphql_metrics = Vec::new();
+        for config in file.graphql_metrics {
+            config.validate().map_err(|e| invalid(format!("Invalid custom metric {}: {}", config.name, e)))?;
+            let labels: Vec<&str> = config.labels.keys().map(String::as_str).collect();
+            let metric = register(&config.name, &config.help, &labels, config.metric_type, metric_obj)?;
+            graphql_metrics.push((config, metric));
+        }
+        info!("Loaded {} SQL and {} GraphQL custom metrics from {}", metrics.len(), graphql_metrics.len(), path);
+        Ok(CustomMetrics { metrics, graphql_metrics })
+    }
+
+    /// Queries every metric at its own interval.
+    pub(crate) async fn run(&self, cfg: &Configuration, metric_obj: &Telemetry, termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+        let sql_metrics = match cfg.hasura_admin {
+            Some(_) => &self.metrics[..],
+            None if self.metrics.is_empty() => &[],
+            None => {
+                warn!("No Hasura admin secret provided, not collecting custom SQL metrics");
+                &[]
+            }
+        };
+
+        tokio::join!(
+            join_all(sql_metrics.iter().map(|(config, metric)| {
+                every(config.interval.unwrap_or(cfg.collect_interval), termination_rx.clone(), move || collect(cfg, metric_obj, config, metric))
+            })),
+            join_all(self.graphql_metrics.iter().map(|(config, metric)| {
+                every(config.interval.unwrap_or(cfg.collect_interval), termination_rx.clone(), move || graphql_metrics::collect(cfg, metric_obj, config, metric))
+            })),
+        );
+        Ok(())
+    }
+}
+
+/// Runs the collection every `interval` milliseconds until terminated.
+async fn every<F, Fut>(interval: u64, mut termination_rx: watch::Receiver<()>, collect: F)
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let mut interval = time::interval(time::Duration::from_millis(interval));
+    loop {
+        tokio::select! {
+            biased;
+            _ = termination_rx.changed() => return,
+            _ = interval.tick() => collect().await,
+        }
+    }
+}
+
+async fn collect(cfg: &Configuration, metric_obj: &Telemetry, config: &CustomMetricConfig, metric: &SnapshotVec) {
+    debug!("Collecting custom metric {}", config.name);
+    let request = SQLRequest {
+        request_type: "bulk".to_string(),
+        args: vec![RunSQLQuery {
+            request_type: match config.kind.as_str() {
+                "postgres" => "run_sql".to_string(),
+                kind => format!("{}_run_sql", kind),
+            },
+            args: RunSQLArgs {
+                source: config.source.clone(),
+                cascade: false,
+                read_only: true,
+                sql: config.sql.clone(),
+            },
+        }],
+    };
+
+    let response = match make_sql_request(&request, cfg).await {
+        Ok(v) if v.status() == reqwest::StatusCode::OK => v.json::<Vec<SQLRowsResult>>().await,
+        Ok(v) => {
+            warn!("Failed to collect custom metric {}, invalid status code: {}", config.name, v.status());
+            metric_obj.ERRORS_TOTAL.with_label_values(&["custom"]).inc();
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to collect custom metric {}: {}", config.name, e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["custom"]).inc();
+            return;
+        }
+    };
+
+    match response.map_err(|e| e.to_string()).and_then(|results| values(config, results)) {
+        Ok(values) => metric.set(values),
+        Err(e) => {
+            warn!("Failed to collect custom metric {}: {}", config.name, e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["custom"]).inc();
+        }
+    }
+}
+
+fn values(config: &CustomMetricConfig, results: Vec<SQLRowsResult>) -> Result<Vec<Sample>, String> {
+    let labels: Vec<&str> = config.labels.iter().map(String::as_str).collect();
+    results.into_iter().next().ok_or("empty response")?.samples(&config.value, &labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_interval_is_rejected() {
+        let file = CustomMetricsFile::parse(
+            "metrics.yaml",
+            "metrics:\n  - name: shop_orders\n    help: Orders\n    sql: SELECT 1 AS count\n    value: count\n    interval: 0\n",
+        )
+        .unwrap();
+        assert!(file.metrics[0].validate().is_err());
+
+        let mut config = file.metrics[0].clone();
+        config.interval = Some(1000);
+        assert!(config.validate().is_ok());
+        config.interval = None;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_by_extension() {
+        let toml = r#"
+            [[metrics]]
+            name = "shop_orders"
+            help = "Orders"
+            sql = "SELECT status, count(*) AS count FROM orders GROUP BY status"
+            value = "count"
+            labels = ["status"]
+            type = "counter"
+
+            [[graphql_metrics]]
+            name = "app_orders"
+            help = "Orders"
+            query = "query { orders_by_status { status count } }"
+            rows = "$.data.orders_by_status[*]"
+            value = "$.count"
+            labels = { status = "$.status" }
+            headers = { x-hasura-role = "monitoring" }
+        "#;
+        let file = CustomMetricsFile::parse("metrics.toml", toml).unwrap();
+        assert_eq!(file.metrics[0].labels, vec!["status".to_string()]);
+        assert_eq!(file.metrics[0].metric_type, CustomMetricType::Counter);
+        assert_eq!(file.graphql_metrics[0].labels["status"], "$.status");
+        assert_eq!(file.graphql_metrics[0].headers["x-hasura-role"], "monitoring");
+
+        assert!(CustomMetricsFile::parse("metrics.yaml", toml).is_err());
+        assert!(CustomMetricsFile::parse("metrics.yml", "metrics: []\n").unwrap().metrics.is_empty());
+    }
+}
@@ -3,54 +3,43 @@ use crate::{Configuration,  Telemetry};
 use log::{warn, info, debug};
 use serde_json::{Map, Value};
 use futures::stream::{self, StreamExt};
-use crate::telemetry::MetricOption;
-
-fn create_event_trigger_request(request_type: &String, source: &String) -> SQLRequest {
-    SQLRequest {
-            request_type: "bulk".to_string(),
-            args: vec![
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE delivered = 'true' OR error = 'true' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE delivered = 'false' AND error = 'false' AND archived = 'false' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = 'true' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = 'false' AND delivered = 'true' GROUP BY trigger_name;".to_string()
-                    }
-                },
-            ],
+use crate::telemetry::{MetricOption, Sample};
+use super::invocations::{check_invocations, InvocationKind, InvocationStats};
+use super::incremental::{EventTable, HighWaterMarks};
+use std::sync::Mutex;
+
+/// The queries of the bulk request of a database.
+#[derive(Clone, Copy)]
+enum EventTriggerQuery {
+    Processed,
+    Pending,
+    Failed,
+    Successful,
+    OldestPendingAge,
+}
+
+impl BulkQuery for EventTriggerQuery {
+    const ALL: &'static [Self] = &[Self::Processed, Self::Pending, Self::Failed, Self::Successful, Self::OldestPendingAge];
+
+    fn counts_settled(self) -> bool {
+        matches!(self, Self::Processed | Self::Failed | Self::Successful)
+    }
+
+    fn sql(self, request_type: &str) -> String {
+        let pending = "delivered = 'false' AND error = 'false' AND archived = 'false'";
+        match self {
+            Self::Processed => "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE delivered = 'true' OR error = 'true' GROUP BY trigger_name;".to_string(),
+            Self::Pending => format!("SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE {} GROUP BY trigger_name;", pending),
+            Self::Failed => "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = 'true' GROUP BY trigger_name;".to_string(),
+            Self::Successful => "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = 'false' AND delivered = 'true' GROUP BY trigger_name;".to_string(),
+            Self::OldestPendingAge => oldest_pending_age_sql(request_type, "hdb_catalog.event_log", "created_at", pending, Some("trigger_name")),
         }
+    }
 }
 
 
-async fn process_database (data_source: &Map<String, Value>,  cfg: &Configuration, metric_obj: &Telemetry) {
+/// Queries the database, its oldest pending ages are appended to `ages`.
+async fn process_database (data_source: &Map<String, Value>,  cfg: &Configuration, metric_obj: &Telemetry, marks: &HighWaterMarks, ages: &Mutex<Vec<Sample>>) -> Option<InvocationStats> {
     let sql_type;
     if let Some(kind) = data_source["kind"].as_str() {
         match kind {
@@ -62,11 +51,16 @@ async fn process_database (data_source: &Map<String, Value>,  cfg: &Configuratio
         sql_type = ""
     }
 
-    if sql_type != "" {
+    // The high-water mark queries rely on postgres functions
+    let incremental = cfg.incremental_counts && sql_type == "run_sql";
+
+    if !sql_type.is_empty() {
         debug!("Querying data from database {}",data_source["name"]);
         if let Some(db_name) = data_source["name"].as_str() {
-            debug!("Request made: {:#?}",serde_json::to_string(&create_event_trigger_request(&sql_type.to_string(), &db_name.to_string())).unwrap());
-            let sql_result = make_sql_request(&create_event_trigger_request(&sql_type.to_string(), &db_name.to_string()), cfg).await;
+            let queries = bulk_queries::<EventTriggerQuery>(incremental);
+            let request = bulk_request(sql_type, db_name, &queries);
+            debug!("Request made: {:#?}",serde_json::to_string(&request).unwrap());
+            let sql_result = make_sql_request(&request, cfg).await;
             match sql_result {
                 Ok(v) => {
                     if v.status() == reqwest::StatusCode::OK {
@@ -74,21 +68,22 @@ async fn process_database (data_source: &Map<String, Value>,  cfg: &Configuratio
                         debug!("Response: {:?}", response);
                         match response {
                             Ok(v) => {
-                                v.iter().enumerate().for_each(|(index, query)| {
-
-                                    let obj = match index as i32 {
-                                        // Index values must match create_event_trigger_request() for coherence
-                                        0 => Ok((MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_FAILED), "failed event triggers")),
-                                        1 => Ok((MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_SUCCESSFUL),"successful event triggers")),
-                                        2 => Ok((MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_PENDING),"pending event triggers")),
-                                        3 => Ok((MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_PROCESSED),"processed event triggers")),
-                                        _ => {
-                                            warn!("Unexpected entry {:?}",query);
-                                            Err(format!("Unexpected entry {:?}",query))
+                                queries.iter().zip(v.iter()).for_each(|(query, result)| {
+                                    let obj = match query {
+                                        EventTriggerQuery::Processed => (MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_PROCESSED), "processed event triggers"),
+                                        EventTriggerQuery::Pending => (MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_PENDING),"pending event triggers"),
+                                        EventTriggerQuery::Failed => (MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_FAILED),"failed event triggers"),
+                                        EventTriggerQuery::Successful => (MetricOption::IntGaugeVec(&metric_obj.EVENT_TRIGGER_SUCCESSFUL),"successful event triggers"),
+                                        EventTriggerQuery::OldestPendingAge => {
+                                            match result.samples(Some(db_name)) {
+                                                Some(samples) => ages.lock().unwrap().extend(samples),
+                                                None => info!("Result of SQL query for 'oldest pending event trigger age' on database {} has failed or is empty: {:?}", db_name, result),
+                                            }
+                                            return;
                                         }
                                     };
 
-                                    process_sql_result(query, obj,Some(db_name));
+                                    process_sql_result(result, obj,Some(db_name));
 
                                 });
                             }
@@ -107,11 +102,40 @@ async fn process_database (data_source: &Map<String, Value>,  cfg: &Configuratio
                     warn!("Failed to collect event triggers check {}", e);
                 }
             };
+
+            if incremental {
+                match marks.settled_events(cfg, EventTable::EventLog, db_name).await {
+                    Ok(settled) => {
+                        for (trigger_name, count) in settled.delivered {
+                            metric_obj.EVENT_TRIGGER_SUCCESSFUL_TOTAL.with_label_values(&[trigger_name.as_str(), db_name]).inc_by(count);
+                        }
+                        for (trigger_name, count) in settled.failed {
+                            metric_obj.EVENT_TRIGGER_FAILED_TOTAL.with_label_values(&[trigger_name.as_str(), db_name]).inc_by(count);
+                        }
+                    }
+                    Err(e) => {
+                        metric_obj.ERRORS_TOTAL.with_label_values(&["event"]).inc();
+                        warn!("Failed to collect settled event trigger events from database {}: {}", db_name, e);
+                    }
+                }
+            }
+
+            // The invocation statistics rely on postgres functions
+            if sql_type == "run_sql" {
+                match check_invocations(cfg, db_name, InvocationKind::EventTriggers, metric_obj.EVENT_TRIGGER_DELIVERY_LATENCY.buckets()).await {
+                    Ok(stats) => return Some(stats.with_label(db_name)),
+                    Err(e) => {
+                        metric_obj.ERRORS_TOTAL.with_label_values(&["event"]).inc();
+                        warn!("Failed to collect event trigger invocations from database {}: {}", db_name, e);
+                    }
+                }
+            }
         }
     }
+    None
 }
 
-pub(crate) async fn check_event_triggers(cfg: &Configuration, metric_obj: &Telemetry, metadata: &Map<String, Value>) {
+pub(crate) async fn check_event_triggers(cfg: &Configuration, metric_obj: &Telemetry, metadata: &Map<String, Value>, marks: &HighWaterMarks) {
     if cfg.disabled_collectors.contains(&crate::Collectors::EventTriggers) {
         info!("Not collecting event triggers.");
         return;
@@ -119,19 +143,37 @@ pub(crate) async fn check_event_triggers(cfg: &Configuration, metric_obj: &Telem
 
     debug!("Processing all the databases to look for event triggers");
 
-    let list_tmp = metadata["metadata"]["sources"].as_array();
+    let list_tmp = metadata.get("metadata").and_then(|metadata| metadata["sources"].as_array());
 
     match list_tmp {
         Some(list) => {
 
+            let invocations = Mutex::new(Vec::new());
+            let ages = Mutex::new(Vec::new());
             let stream = stream::iter(list);
-            stream.for_each_concurrent(cfg.concurrency_limit, |data_source| async move {
-
-                debug!("Processing database {} of kind {}",data_source["name"],data_source["kind"]);
-                process_database(data_source.as_object().unwrap(), cfg, metric_obj).await;
-                debug!("Processed database {} of kind {}",data_source["name"],data_source["kind"]);
-
+            stream.for_each_concurrent(cfg.concurrency_limit, |data_source| {
+                let invocations = &invocations;
+                let ages = &ages;
+                async move {
+                    debug!("Processing database {} of kind {}",data_source["name"],data_source["kind"]);
+                    if let Some(stats) = process_database(data_source.as_object().unwrap(), cfg, metric_obj, marks, ages).await {
+                        invocations.lock().unwrap().push(stats);
+                    }
+                    debug!("Processed database {} of kind {}",data_source["name"],data_source["kind"]);
+                }
             }).await;
+
+            // Replaced at once, so triggers without pending events or invocations in the window disappear
+            metric_obj.EVENT_TRIGGER_OLDEST_PENDING_AGE.set(ages.into_inner().unwrap());
+            let (mut latency, mut count, mut retries) = (vec![], vec![], vec![]);
+            for stats in invocations.into_inner().unwrap() {
+                latency.extend(stats.latency);
+                count.extend(stats.invocations);
+                retries.extend(stats.retries);
+            }
+            metric_obj.EVENT_TRIGGER_DELIVERY_LATENCY.set(latency);
+            metric_obj.EVENT_TRIGGER_INVOCATIONS.set(count);
+            metric_obj.EVENT_TRIGGER_RETRIES.set(retries);
         }
         None => {
             metric_obj.ERRORS_TOTAL.with_label_values(&["event"]).inc();
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::custom_metrics::CustomMetricType;
+use crate::telemetry::{json_to_sample, unique_samples, Sample, SnapshotVec};
+use crate::{jsonpath, Configuration, Telemetry};
+
+/// A metric published from the response of a GraphQL query, run with the given
+/// headers (e.g. `x-hasura-role`) so the permissions of that role apply.
+#[derive(Deserialize, Clone, Debug)]
+pub struct GraphQLMetricConfig {
+    pub name: String,
+    pub help: String,
+    pub query: String,
+    #[serde(default)]
+    pub variables: Option<Value>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// The JSON path of the rows in the response, each becomes a sample
+    #[serde(default = "default_rows")]
+    pub rows: String,
+    /// The JSON path of the value, relative to the row
+    pub value: String,
+    /// The JSON paths of the label values per label name, relative to the row
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(rename = "type", default)]
+    pub metric_type: CustomMetricType,
+    /// Milliseconds between the queries, the collect interval by default
+    pub interval: Option<u64>,
+}
+
+fn default_rows() -> String {
+    "$".to_string()
+}
+
+impl GraphQLMetricConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == Some(0) {
+            return Err("interval must be at least 1 millisecond".to_string());
+        }
+        jsonpath::parse(&self.rows)?;
+        jsonpath::parse(&self.value)?;
+        for path in self.labels.values() {
+            jsonpath::parse(path)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn collect(cfg: &Configuration, metric_obj: &Telemetry, config: &GraphQLMetricConfig, metric: &SnapshotVec) {
+    debug!("Collecting custom metric {}", config.name);
+    match query(cfg, config).await.and_then(|response| values(config, &response)) {
+        Ok(values) => metric.set(values),
+        Err(e) => {
+            warn!("Failed to collect custom metric {}: {}", config.name, e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["custom"]).inc();
+        }
+    }
+}
+
+async fn query(cfg: &Configuration, config: &GraphQLMetricConfig) -> Result<Value, String> {
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/v1/graphql", cfg.hasura_addr))
+        .json(&json!({ "query": config.query, "variables": config.variables }));
+    if let Some(admin_secret) = cfg.hasura_admin.as_deref() {
+        request = request.header("x-hasura-admin-secret", admin_secret);
+    }
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(format!("invalid status code: {}", response.status()));
+    }
+    let response = response.json::<Value>().await.map_err(|e| e.to_string())?;
+    // GraphQL errors are reported with a 200 status
+    if let Some(error) = response.get("errors").and_then(|errors| errors.get(0)) {
+        return Err(error.get("message").and_then(Value::as_str).unwrap_or("query failed").to_string());
+    }
+    Ok(response)
+}
+
+fn values(config: &GraphQLMetricConfig, response: &Value) -> Result<Vec<Sample>, String> {
+    let value_path = jsonpath::parse(&config.value)?;
+    let label_paths = config.labels.values().map(|path| jsonpath::parse(path)).collect::<Result<Vec<_>, _>>()?;
+
+    // Rows without a numeric value have no sample
+    Ok(unique_samples(jsonpath::select(response, &jsonpath::parse(&config.rows)?).into_iter().filter_map(|row| {
+        json_to_sample(jsonpath::select(row, &value_path).first().copied(), label_paths.iter().map(|path| jsonpath::select(row, path).first().copied()))
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rows: &str, value: &str, labels: &[(&str, &str)]) -> GraphQLMetricConfig {
+        GraphQLMetricConfig {
+            name: "app_orders".to_string(),
+            help: String::new(),
+            query: String::new(),
+            variables: None,
+            headers: BTreeMap::new(),
+            rows: rows.to_string(),
+            value: value.to_string(),
+            labels: labels.iter().map(|(name, path)| (name.to_string(), path.to_string())).collect(),
+            metric_type: CustomMetricType::Gauge,
+            interval: None,
+        }
+    }
+
+    #[test]
+    fn values_of_rows() {
+        let response = json!({ "data": { "orders": [
+            { "status": "open", "count": 2 },
+            { "status": "closed", "count": "3" },
+            { "status": null, "count": null },
+            { "status": "open", "count": 4 },
+        ] } });
+        let samples = values(&config("$.data.orders[*]", "$.count", &[("status", "$.status")]), &response).unwrap();
+        assert_eq!(samples, vec![(vec!["closed".to_string()], 3.0), (vec!["open".to_string()], 2.0)]);
+    }
+
+    #[test]
+    fn values_of_invalid_path() {
+        assert!(values(&config("$.data..orders", "$.count", &[]), &json!({})).is_err());
+    }
+
+    #[test]
+    fn zero_interval_is_rejected() {
+        let mut config = config("$", "$.count", &[]);
+        assert!(config.validate().is_ok());
+        config.interval = Some(0);
+        assert!(config.validate().is_err());
+    }
+}
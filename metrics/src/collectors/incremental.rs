@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::sql::*;
+use crate::Configuration;
+
+/// A table of events, whose settled events are counted by the time of their last
+/// invocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum EventTable {
+    EventLog,
+    CronEvents,
+    ScheduledEvents,
+}
+
+/// Invocations logged within this delay of the count may still be uncommitted, or their
+/// event not yet marked as settled, so they are left to the next count.
+const SETTLE_DELAY: &str = "10 seconds";
+
+/// The longest time counted at once, so a count after a long outage does not read the
+/// whole invocation log. Events settled before it are never counted.
+const MAX_WINDOW: &str = "1 hour";
+
+struct Columns {
+    events: &'static str,
+    invocations: &'static str,
+    trigger: &'static str,
+    delivered: &'static str,
+    failed: &'static str,
+}
+
+impl EventTable {
+    fn columns(self) -> Columns {
+        match self {
+            EventTable::EventLog => Columns {
+                events: "hdb_catalog.event_log",
+                invocations: "hdb_catalog.event_invocation_logs",
+                trigger: "e.trigger_name",
+                delivered: "e.delivered AND NOT e.error",
+                failed: "e.error",
+            },
+            EventTable::CronEvents => Columns {
+                events: "hdb_catalog.hdb_cron_events",
+                invocations: "hdb_catalog.hdb_cron_event_invocation_logs",
+                trigger: "e.trigger_name",
+                delivered: "e.status = 'delivered'",
+                failed: "e.status = 'error'",
+            },
+            EventTable::ScheduledEvents => Columns {
+                events: "hdb_catalog.hdb_scheduled_events",
+                invocations: "hdb_catalog.hdb_scheduled_event_invocation_logs",
+                trigger: "''",
+                delivered: "e.status = 'delivered'",
+                failed: "e.status = 'error'",
+            },
+        }
+    }
+}
+
+/// The events settled since the previous count.
+#[derive(Debug, Default)]
+pub(crate) struct SettledEvents {
+    /// The number of delivered events per trigger name, empty for one off events
+    pub delivered: Vec<(String, u64)>,
+    /// The number of failed events per trigger name, empty for one off events
+    pub failed: Vec<(String, u64)>,
+}
+
+/// The high-water marks per table and source: every invocation up to its mark is
+/// counted, so only the invocations after it are read.
+#[derive(Default)]
+pub(crate) struct HighWaterMarks {
+    marks: Mutex<HashMap<(EventTable, String), String>>,
+}
+
+fn query(source: &str, sql: String) -> SQLRequest {
+    SQLRequest {
+        request_type: "bulk".to_string(),
+        args: vec![RunSQLQuery {
+            request_type: "run_sql".to_string(),
+            args: RunSQLArgs {
+                source: source.to_string(),
+                cascade: false,
+                read_only: true,
+                sql,
+            },
+        }],
+    }
+}
+
+/// Counts the events settled by their last invocation after the mark, and returns the
+/// new mark on every row, with a row without outcome if none settled. An event is only
+/// counted again if it is invoked again, e.g. when it is redelivered.
+fn settled_events_sql(columns: &Columns, mark: &str) -> String {
+    let Columns { events, invocations, trigger, delivered, failed } = columns;
+    let mark = mark.replace('\'', "''");
+    format!("WITH bounds AS (SELECT GREATEST('{mark}'::timestamptz, NOW() - interval '{MAX_WINDOW}') AS lower, NOW() - interval '{SETTLE_DELAY}' AS upper), \
+        settled AS (SELECT {trigger} AS trigger_name, CASE WHEN {failed} THEN 'failed' ELSE 'delivered' END AS outcome \
+        FROM bounds, {invocations} i JOIN {events} e ON e.id = i.event_id \
+        WHERE i.created_at > bounds.lower AND i.created_at <= bounds.upper AND ({delivered} OR {failed}) \
+        AND NOT EXISTS (SELECT 1 FROM {invocations} later WHERE later.event_id = i.event_id AND (later.created_at, later.id) > (i.created_at, i.id))) \
+        SELECT settled.trigger_name, settled.outcome, COUNT(settled.outcome) AS value, bounds.upper::text AS mark FROM bounds LEFT JOIN settled ON true GROUP BY 1, 2, 4;")
+}
+
+async fn run_query(cfg: &Configuration, source: &str, sql: String, labels: &[&str]) -> Result<Vec<crate::telemetry::Sample>, String> {
+    let response = make_sql_request(&query(source, sql), cfg).await.map_err(|e| e.to_string())?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(format!("invalid status code: {}", response.status()));
+    }
+    let results = response.json::<Vec<SQLRowsResult>>().await.map_err(|e| e.to_string())?;
+    results.into_iter().next().ok_or("empty response")?.samples("value", labels)
+}
+
+impl HighWaterMarks {
+    /// The events of the postgres source settled since the previous call, none on the
+    /// first call, which only places the mark.
+    pub(crate) async fn settled_events(&self, cfg: &Configuration, table: EventTable, source: &str) -> Result<SettledEvents, String> {
+        let key = (table, source.to_string());
+        // Nothing is after infinity, so the first count only returns the mark
+        let mark = self.marks.lock().unwrap().get(&key).cloned().unwrap_or_else(|| "infinity".to_string());
+
+        let rows = run_query(cfg, source, settled_events_sql(&table.columns(), &mark), &["trigger_name", "outcome", "mark"]).await?;
+        let mut settled = SettledEvents::default();
+        for (labels, value) in rows {
+            let (trigger_name, outcome, mark) = (&labels[0], &labels[1], &labels[2]);
+            match outcome.as_str() {
+                "failed" => settled.failed.push((trigger_name.clone(), value as u64)),
+                "delivered" => settled.delivered.push((trigger_name.clone(), value as u64)),
+                _ => {}
+            }
+            self.marks.lock().unwrap().insert(key.clone(), mark.clone());
+        }
+        Ok(settled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settled_events_sql_reads_invocations_after_the_mark() {
+        let sql = settled_events_sql(&EventTable::EventLog.columns(), "2024-01-01 00:00:00+00");
+        assert!(sql.contains("GREATEST('2024-01-01 00:00:00+00'::timestamptz, NOW() - interval '1 hour') AS lower"));
+        assert!(sql.contains("NOW() - interval '10 seconds' AS upper"));
+        assert!(sql.contains("FROM bounds, hdb_catalog.event_invocation_logs i JOIN hdb_catalog.event_log e ON e.id = i.event_id"));
+        assert!(sql.contains("WHERE i.created_at > bounds.lower AND i.created_at <= bounds.upper AND (e.delivered AND NOT e.error OR e.error)"));
+        // Only the last invocation of an event counts
+        assert!(sql.contains("NOT EXISTS (SELECT 1 FROM hdb_catalog.event_invocation_logs later WHERE later.event_id = i.event_id"));
+        assert!(sql.ends_with("bounds.upper::text AS mark FROM bounds LEFT JOIN settled ON true GROUP BY 1, 2, 4;"));
+    }
+
+    #[test]
+    fn settled_events_sql_of_one_off_events_has_no_trigger() {
+        let sql = settled_events_sql(&EventTable::ScheduledEvents.columns(), "infinity");
+        assert!(sql.contains("SELECT '' AS trigger_name, CASE WHEN e.status = 'error' THEN 'failed' ELSE 'delivered' END AS outcome"));
+        assert!(sql.contains("hdb_catalog.hdb_scheduled_event_invocation_logs i JOIN hdb_catalog.hdb_scheduled_events e"));
+    }
+
+    #[test]
+    fn settled_events_sql_escapes_the_mark() {
+        let sql = settled_events_sql(&EventTable::CronEvents.columns(), "x'; DROP TABLE t; --");
+        assert!(sql.contains("GREATEST('x''; DROP TABLE t; --'::timestamptz"));
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use log::{debug, info};
+use serde_json::Value;
+
+use crate::telemetry::Sample;
+use crate::{Configuration, Telemetry};
+
+/// Counts the objects per label values.
+#[derive(Default)]
+struct Counts(BTreeMap<Vec<String>, f64>);
+
+impl Counts {
+    fn add(&mut self, labels: &[&str], count: usize) {
+        *self.0.entry(labels.iter().map(|label| label.to_string()).collect()).or_insert(0.0) += count as f64;
+    }
+
+    fn samples(self) -> Vec<Sample> {
+        self.0.into_iter().collect()
+    }
+}
+
+fn len(value: &Value) -> usize {
+    value.as_array().map(Vec::len).unwrap_or_default()
+}
+
+fn array(value: &Value) -> impl Iterator<Item = &Value> {
+    value.as_array().into_iter().flatten()
+}
+
+/// Sets the inventory gauges from the `metadata` of the export, the series of removed
+/// objects disappear.
+pub(crate) fn check_metadata_inventory(cfg: &Configuration, metric_obj: &Telemetry, metadata: Option<&Value>) {
+    if cfg.disabled_collectors.contains(&crate::Collectors::MetadataInventory) {
+        info!("Not collecting metadata inventory.");
+        return;
+    }
+    // The metadata could not be exported, the last inventory is kept
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return,
+    };
+    debug!("Counting the metadata inventory");
+
+    let (mut sources, mut tables, mut relationships, mut permissions, mut event_triggers) =
+        (Counts::default(), Counts::default(), Counts::default(), Counts::default(), Counts::default());
+    for source in array(&metadata["sources"]) {
+        let name = source["name"].as_str().unwrap_or_default();
+        sources.add(&[source["kind"].as_str().unwrap_or_default()], 1);
+        tables.add(&[name], len(&source["tables"]));
+        for table in array(&source["tables"]) {
+            for (relationship_type, key) in [("object", "object_relationships"), ("array", "array_relationships"), ("remote", "remote_relationships")] {
+                relationships.add(&[name, relationship_type], len(&table[key]));
+            }
+            for action in ["insert", "select", "update", "delete"] {
+                for permission in array(&table[format!("{}_permissions", action)]) {
+                    permissions.add(&[name, permission["role"].as_str().unwrap_or_default(), action], 1);
+                }
+            }
+            event_triggers.add(&[name], len(&table["event_triggers"]));
+        }
+    }
+
+    let mut actions = Counts::default();
+    for action in array(&metadata["actions"]) {
+        // The defaults of hasura for actions declared without them
+        let action_type = action["definition"]["type"].as_str().unwrap_or("mutation");
+        let kind = action["definition"]["kind"].as_str().unwrap_or("synchronous");
+        actions.add(&[action_type, kind], 1);
+    }
+
+    metric_obj.METADATA_SOURCES.set(sources.samples());
+    metric_obj.METADATA_TABLES.set(tables.samples());
+    metric_obj.METADATA_RELATIONSHIPS.set(relationships.samples());
+    metric_obj.METADATA_PERMISSIONS.set(permissions.samples());
+    metric_obj.METADATA_EVENT_TRIGGERS.set(event_triggers.samples());
+    metric_obj.METADATA_ACTIONS.set(actions.samples());
+    metric_obj.METADATA_REMOTE_SCHEMAS.set(len(&metadata["remote_schemas"]) as i64);
+    metric_obj.METADATA_CRON_TRIGGERS.set(len(&metadata["cron_triggers"]) as i64);
+    metric_obj.METADATA_REST_ENDPOINTS.set(len(&metadata["rest_endpoints"]) as i64);
+    metric_obj.METADATA_QUERY_COLLECTIONS.set(len(&metadata["query_collections"]) as i64);
+    metric_obj.METADATA_ALLOWLIST_ENTRIES.set(len(&metadata["allowlist"]) as i64);
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use super::sql::*;
+use crate::telemetry::{HistogramSample, Sample};
+use crate::Configuration;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum InvocationKind {
+    EventTriggers,
+    CronTriggers,
+    ScheduledEvents,
+}
+
+/// The statistics of the invocations within the window, labeled by trigger name
+/// except for one off events.
+pub(crate) struct InvocationStats {
+    /// The histogram of the delay from the event (or its scheduled time) to its invocations
+    pub latency: Vec<HistogramSample>,
+    /// The number of invocations per webhook response status
+    pub invocations: Vec<Sample>,
+    /// The tries beyond the first of the events invoked
+    pub retries: Vec<Sample>,
+}
+
+impl InvocationStats {
+    /// Appends the label value to all samples.
+    pub fn with_label(mut self, value: &str) -> Self {
+        for samples in [&mut self.invocations, &mut self.retries] {
+            samples.iter_mut().for_each(|(labels, _)| labels.push(value.to_string()));
+        }
+        self.latency.iter_mut().for_each(|sample| sample.labels.push(value.to_string()));
+        self
+    }
+}
+
+fn create_invocation_request(source: &str, kind: InvocationKind, window: u64, buckets: &[f64]) -> SQLRequest {
+    let (invocations, events, trigger, since) = match kind {
+        InvocationKind::EventTriggers => ("hdb_catalog.event_invocation_logs", "hdb_catalog.event_log", Some("e.trigger_name, "), "e.created_at"),
+        InvocationKind::CronTriggers => ("hdb_catalog.hdb_cron_event_invocation_logs", "hdb_catalog.hdb_cron_events", Some("e.trigger_name, "), "e.scheduled_time"),
+        InvocationKind::ScheduledEvents => ("hdb_catalog.hdb_scheduled_event_invocation_logs", "hdb_catalog.hdb_scheduled_events", None, "e.scheduled_time"),
+    };
+    let trigger = trigger.unwrap_or_default();
+    let window = format!("i.created_at > NOW() - interval '{} milliseconds'", window);
+    let group_by = if trigger.is_empty() { "" } else { " GROUP BY e.trigger_name" };
+    let delay = format!("EXTRACT(EPOCH FROM i.created_at - {since})");
+    // The cumulative count of each bucket by its index, the last one is `+Inf`
+    let bounds: Vec<String> = buckets.iter().map(|le| format!("{le:?}"))
+        .chain(["'Infinity'".to_string()])
+        .enumerate()
+        .map(|(index, le)| format!("({index}, {le}::float8)"))
+        .collect();
+    let bounds = bounds.join(", ");
+
+    let query = |sql: String| RunSQLQuery {
+        request_type: "run_sql".to_string(),
+        args: RunSQLArgs {
+            source: source.to_string(),
+            cascade: false,
+            read_only: true,
+            sql,
+        },
+    };
+    SQLRequest {
+        request_type: "bulk".to_string(),
+        args: vec![
+            query(format!("SELECT {trigger}b.index::text AS bucket, COUNT(*) FILTER (WHERE {delay} <= b.le) AS value FROM {invocations} i JOIN {events} e ON e.id = i.event_id CROSS JOIN (VALUES {bounds}) AS b(index, le) WHERE {window} GROUP BY {trigger}b.index;")),
+            query(format!("SELECT {trigger}COALESCE(SUM({delay}), 0) AS value FROM {invocations} i JOIN {events} e ON e.id = i.event_id WHERE {window}{group_by};")),
+            query(format!("SELECT {trigger}COALESCE(i.status::text, '') AS status, COUNT(*) AS value FROM {invocations} i JOIN {events} e ON e.id = i.event_id WHERE {window} GROUP BY {trigger}i.status;")),
+            query(format!("SELECT {trigger}COALESCE(SUM(GREATEST(e.tries - 1, 0)), 0) AS value FROM {events} e WHERE EXISTS (SELECT 1 FROM {invocations} i WHERE i.event_id = e.id AND {window}){group_by};")),
+        ],
+    }
+}
+
+/// Queries the invocation logs of the postgres source, over the invocation window.
+pub(crate) async fn check_invocations(cfg: &Configuration, source: &str, kind: InvocationKind, buckets: &[f64]) -> Result<InvocationStats, String> {
+    let request = create_invocation_request(source, kind, cfg.invocation_window, buckets);
+    let response = make_sql_request(&request, cfg).await.map_err(|e| e.to_string())?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(format!("invalid status code: {}", response.status()));
+    }
+    let mut results = response.json::<Vec<SQLRowsResult>>().await.map_err(|e| e.to_string())?.into_iter();
+    let mut next = |labels: &[&str]| match results.next() {
+        Some(result) => result.samples("value", labels),
+        None => Err("missing result".to_string()),
+    };
+
+    let trigger: &[&str] = match kind {
+        InvocationKind::ScheduledEvents => &[],
+        _ => &["trigger_name"],
+    };
+    Ok(InvocationStats {
+        latency: histograms(next(&[trigger, &["bucket"]].concat())?, next(trigger)?, buckets.len() + 1),
+        invocations: next(&[trigger, &["status"]].concat())?,
+        retries: next(trigger)?,
+    })
+}
+
+/// The histograms of the bucket samples, whose last label is the bucket index, and of
+/// the sums. Series without a sum or with missing buckets are incomplete and skipped.
+fn histograms(bucket_samples: Vec<Sample>, sums: Vec<Sample>, bucket_count: usize) -> Vec<HistogramSample> {
+    let mut buckets: BTreeMap<Vec<String>, Vec<Option<u64>>> = BTreeMap::new();
+    for (mut labels, count) in bucket_samples {
+        let index = labels.pop().and_then(|index| index.parse::<usize>().ok()).filter(|index| *index < bucket_count);
+        if let Some(index) = index {
+            buckets.entry(labels).or_insert_with(|| vec![None; bucket_count])[index] = Some(count as u64);
+        }
+    }
+    sums.into_iter()
+        .filter_map(|(labels, sum)| {
+            let counts = buckets.remove(&labels)?.into_iter().collect::<Option<Vec<u64>>>()?;
+            Some(HistogramSample { labels, buckets: counts, sum })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_of_buckets_and_sums() {
+        let bucket = |trigger: &str, index: &str, count: f64| (vec![trigger.to_string(), index.to_string()], count);
+        let samples = vec![
+            bucket("a", "1", 2.0),
+            bucket("a", "0", 1.0),
+            bucket("a", "2", 3.0),
+            bucket("b", "0", 0.0),
+            bucket("b", "2", 1.0),
+            bucket("c", "0", 1.0),
+            bucket("c", "1", 1.0),
+            bucket("c", "2", 1.0),
+        ];
+        let sums = vec![(vec!["a".to_string()], 12.5), (vec!["b".to_string()], 600.0)];
+        assert_eq!(
+            histograms(samples, sums, 3),
+            vec![HistogramSample { labels: vec!["a".to_string()], buckets: vec![1, 2, 3], sum: 12.5 }],
+        );
+    }
+
+    #[test]
+    fn latency_buckets_end_at_infinity() {
+        let request = create_invocation_request("default", InvocationKind::ScheduledEvents, 60000, &[0.5, 10.0]);
+        let sql = &request.args[0].args.sql;
+        assert!(sql.contains("(VALUES (0, 0.5::float8), (1, 10.0::float8), (2, 'Infinity'::float8))"), "{}", sql);
+        assert!(!sql.contains("GROUP BY e.trigger_name"), "{}", sql);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::{Configuration, Telemetry};
 use log::{warn,debug};
@@ -25,7 +25,58 @@ impl MetadataCheckRequest {
 #[derive(Deserialize)]
 pub struct MetadataCheckResponse {
     #[serde(rename = "is_consistent")]
-    pub is_consistent: bool
+    pub is_consistent: bool,
+    #[serde(rename = "inconsistent_objects", default)]
+    pub inconsistent_objects: Vec<InconsistentObject>,
+}
+
+#[derive(Deserialize)]
+pub struct InconsistentObject {
+    #[serde(rename = "type")]
+    pub object_type: String,
+    /// A description like `object_relation author in table article in source default`,
+    /// missing on older versions
+    #[serde(rename = "name", default)]
+    pub name: Option<String>,
+    #[serde(rename = "reason", default)]
+    pub reason: Value,
+    #[serde(rename = "definition", default)]
+    pub definition: Value,
+}
+
+impl InconsistentObject {
+    fn name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        match &self.definition {
+            Value::String(name) => name.clone(),
+            definition => definition["name"].as_str().unwrap_or_default().to_string(),
+        }
+    }
+
+    /// The category of the reason, the reason itself is free text that would make a
+    /// new series for every variation.
+    fn reason_category(&self) -> &'static str {
+        let reason = match &self.reason {
+            Value::String(reason) => reason.to_lowercase(),
+            reason => reason.to_string().to_lowercase(),
+        };
+        let matches = |patterns: &[&str]| patterns.iter().any(|pattern| reason.contains(pattern));
+        if matches(&["http exception", "connection", "timeout", "timed out", "could not connect"]) {
+            "unreachable"
+        } else if matches(&["does not exist", "not found", "no such", "missing", "undefined"]) {
+            "missing"
+        } else if matches(&["already exists", "conflict", "duplicate"]) {
+            "conflict"
+        } else if matches(&["permission", "denied", "unauthorized", "forbidden"]) {
+            "permission"
+        } else if matches(&["invalid", "unexpected", "mismatch", "validation", "parse"]) {
+            "invalid"
+        } else {
+            "other"
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -39,7 +90,7 @@ pub struct MetadataExportRequest {
 }
 
 impl MetadataExportRequest {
-    fn export_metadata() -> Self {
+    pub(crate) fn export_metadata() -> Self {
         MetadataExportRequest {
             request_type: "export_metadata".to_string(),
             version: 2,
@@ -91,7 +142,7 @@ async fn fetch_metadata_consistency(cfg: &Configuration, metric_obj: &Telemetry)
     if cfg.disabled_collectors.contains(&crate::Collectors::MetadataInconsistency) {
         return consistency;
     }
-    let admin_secret = match &cfg.hasura_admin {
+    let admin_secret = match cfg.hasura_admin.as_deref() {
         Some(v) => v,
         None => {
             warn!("Metadata should be collected, but admin secret missing!");
@@ -119,6 +170,13 @@ async fn fetch_metadata_consistency(cfg: &Configuration, metric_obj: &Telemetry)
                         } else {
                             metric_obj.METADATA_CONSISTENCY.set(0);
                         }
+                        metric_obj.METADATA_INCONSISTENT_OBJECTS.set(v.inconsistent_objects.len() as i64);
+                        // Objects with the same labels are summed, so no series is duplicated
+                        let mut objects = BTreeMap::new();
+                        for object in &v.inconsistent_objects {
+                            *objects.entry(vec![object.object_type.clone(), object.name(), object.reason_category().to_string()]).or_insert(0.0) += 1.0;
+                        }
+                        metric_obj.METADATA_INCONSISTENT_OBJECT.set(objects.into_iter().collect());
                     },
                     Err(e) => {
                         warn!("Failed to collect metadata check invalid response format: {}", e);
@@ -136,7 +194,7 @@ async fn fetch_metadata_consistency(cfg: &Configuration, metric_obj: &Telemetry)
         }
     };
 
-    return consistency;
+    consistency
 }
 
 
@@ -144,11 +202,12 @@ async fn fetch_metadata(cfg: &Configuration, metric_obj: &Telemetry) -> Map<Stri
     
     let mut metadata = json!({}).as_object().unwrap().clone();
     
-    if cfg.disabled_collectors.contains(&crate::Collectors::EventTriggers) {
+    let export_collectors = [crate::Collectors::EventTriggers, crate::Collectors::RemoteSchemas, crate::Collectors::MetadataInventory];
+    if export_collectors.iter().all(|collector| cfg.disabled_collectors.contains(collector)) {
         return metadata;
     }
     
-    let admin_secret = match &cfg.hasura_admin {
+    let admin_secret = match cfg.hasura_admin.as_deref() {
         Some(v) => v,
         None => {
             warn!("Metadata should be collected, but admin secret missing!");
@@ -186,20 +245,32 @@ async fn fetch_metadata(cfg: &Configuration, metric_obj: &Telemetry) -> Map<Stri
             warn!("Failed to collect metadata check {}", e);
         }
     };
-    return metadata;
+    metadata
 }
 
-pub(crate) async fn check_metadata(cfg: &Configuration, metric_obj: &Telemetry) -> Map<String, Value> {
+/// The exported metadata if it is consistent, and the `metadata` of the export even if it
+/// is inconsistent, for the collectors reporting on it, as e.g. a failing remote schema
+/// makes it inconsistent. `None` if the metadata could not be exported.
+pub(crate) async fn check_metadata(cfg: &Configuration, metric_obj: &Telemetry) -> (Map<String, Value>, Option<Value>) {
     let mut metadata = json!({}).as_object().unwrap().clone();
+    let mut exported = None;
+    let report_collectors = [crate::Collectors::RemoteSchemas, crate::Collectors::MetadataInventory];
 
     tokio::join!(
         fetch_version(cfg, metric_obj),
         async {
             let consistent = fetch_metadata_consistency(cfg, metric_obj).await;
 
+            if consistent || report_collectors.iter().any(|collector| !cfg.disabled_collectors.contains(collector)) {
+                let export = fetch_metadata(cfg, metric_obj).await;
+                exported = export.get("metadata").cloned();
+                if consistent {
+                    metadata = export;
+                }
+            }
+
             if consistent {
                 debug!("Metadata is consistent");
-                metadata = fetch_metadata(cfg, metric_obj).await
             } else {
                 warn!("Failed to collect metadata because it is inconsistent");
                 metric_obj.ERRORS_TOTAL.with_label_values(&["metadata"]).inc();
@@ -207,5 +278,5 @@ pub(crate) async fn check_metadata(cfg: &Configuration, metric_obj: &Telemetry)
         }
     );
 
-    return metadata;
+    (metadata, exported)
 }
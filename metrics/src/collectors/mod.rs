@@ -5,12 +5,25 @@ use crate::{Configuration, Telemetry};
 mod sql;
 mod health;
 mod metadata;
+mod catalog;
 mod scheduled_events;
 mod cron_triggers;
 mod event_triggers;
+mod async_actions;
+mod remote_schemas;
+mod inventory;
+mod invocations;
+mod incremental;
+mod custom_metrics;
+mod graphql_metrics;
+
+pub(crate) use custom_metrics::CustomMetrics;
 
 pub(crate) async fn run_metadata_collector(cfg: &Configuration, metric_obj: &Telemetry, mut termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
     let mut interval = time::interval(time::Duration::from_millis(cfg.collect_interval));
+    let mut catalog = None;
+    let marks = incremental::HighWaterMarks::default();
+    let probes = remote_schemas::RemoteSchemaProbes::default();
 
     loop {
         tokio::select! {
@@ -19,14 +32,22 @@ pub(crate) async fn run_metadata_collector(cfg: &Configuration, metric_obj: &Tel
 
             _ = interval.tick() => {
                 debug!("Running metadata collector");
+                if catalog.is_none() {
+                    catalog = catalog::resolve_catalog_source(cfg, metric_obj).await;
+                }
 
                 tokio::join!(
                     health::check_health(cfg,metric_obj),
-                    scheduled_events::check_scheduled_events(&cfg,metric_obj),
-                    cron_triggers::check_cron_triggers(&cfg,metric_obj),
+                    scheduled_events::check_scheduled_events(cfg,metric_obj,catalog.as_ref(),&marks),
+                    cron_triggers::check_cron_triggers(cfg,metric_obj,catalog.as_ref(),&marks),
+                    async_actions::check_async_actions(cfg,metric_obj,catalog.as_ref()),
                     async {
-                        let metadata = metadata::check_metadata(cfg,metric_obj).await;
-                        event_triggers::check_event_triggers(&cfg,metric_obj, &metadata).await;
+                        let (metadata, exported) = metadata::check_metadata(cfg,metric_obj).await;
+                        inventory::check_metadata_inventory(cfg,metric_obj, exported.as_ref());
+                        tokio::join!(
+                            event_triggers::check_event_triggers(cfg,metric_obj, &metadata, &marks),
+                            remote_schemas::check_remote_schemas(cfg,metric_obj, exported.as_ref(), &probes),
+                        );
                     }
                 );
             },
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{self, StreamExt};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+
+use crate::{Configuration, Telemetry};
+
+/// The last error category per remote schema, kept after it recovers.
+#[derive(Default)]
+pub(crate) struct RemoteSchemaProbes {
+    last_errors: Mutex<BTreeMap<String, (&'static str, f64)>>,
+}
+
+/// A failed probe, the category is a label value, while the message is only logged.
+struct ProbeError {
+    category: &'static str,
+    message: String,
+}
+
+impl ProbeError {
+    fn new(category: &'static str, message: impl ToString) -> Self {
+        ProbeError { category, message: message.to_string() }
+    }
+
+    fn request(e: reqwest::Error) -> Self {
+        let category = if e.is_timeout() {
+            "timeout"
+        } else if e.is_decode() || e.is_body() {
+            "invalid_response"
+        } else {
+            "connect"
+        };
+        ProbeError::new(category, e)
+    }
+}
+
+/// Reads a value of the definition, given directly or by an environment variable, which
+/// must also be set for the adapter.
+fn from_env(definition: &Value, key: &str) -> Result<Option<String>, ProbeError> {
+    if let Some(value) = definition[key].as_str() {
+        return Ok(Some(value.to_string()));
+    }
+    match definition[format!("{}_from_env", key)].as_str() {
+        Some(name) => std::env::var(name).map(Some).map_err(|_| ProbeError::new("config", format!("environment variable {} is not set", name))),
+        None => Ok(None),
+    }
+}
+
+/// Posts the ping query to the remote schema, with the headers of its definition.
+async fn probe(cfg: &Configuration, definition: &Value) -> Result<Duration, ProbeError> {
+    let url = from_env(definition, "url")?.ok_or_else(|| ProbeError::new("config", "no url"))?;
+    // Bounded by the collect interval, so a hanging remote schema does not delay the next collection
+    let timeout = Duration::from_secs(definition["timeout_seconds"].as_u64().unwrap_or(60)).min(Duration::from_millis(cfg.collect_interval));
+
+    let mut request = reqwest::Client::new()
+        .post(url)
+        .timeout(timeout)
+        .json(&json!({ "query": cfg.remote_schema_ping_query }));
+    for header in definition["headers"].as_array().into_iter().flatten() {
+        if let (Some(name), Some(value)) = (header["name"].as_str(), from_env(header, "value")?) {
+            request = request.header(name, value);
+        }
+    }
+
+    let start = Instant::now();
+    let response = request.send().await.map_err(ProbeError::request)?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(ProbeError::new("http_status", format!("invalid status code: {}", response.status())));
+    }
+    let response = response.json::<Value>().await.map_err(ProbeError::request)?;
+    let duration = start.elapsed();
+    if let Some(error) = response.get("errors").and_then(|errors| errors.get(0)) {
+        return Err(ProbeError::new("graphql_error", error.get("message").and_then(Value::as_str).unwrap_or("query failed")));
+    }
+    Ok(duration)
+}
+
+pub(crate) async fn check_remote_schemas(cfg: &Configuration, metric_obj: &Telemetry, metadata: Option<&Value>, probes: &RemoteSchemaProbes) {
+    if cfg.disabled_collectors.contains(&crate::Collectors::RemoteSchemas) {
+        info!("Not collecting remote schemas.");
+        return;
+    }
+    // The metadata could not be exported, the last probes are kept
+    let remote_schemas = match metadata {
+        Some(metadata) => metadata["remote_schemas"].as_array().map(Vec::as_slice).unwrap_or_default(),
+        None => return,
+    };
+
+    debug!("Probing {} remote schemas", remote_schemas.len());
+    let results = Mutex::new(Vec::new());
+    stream::iter(remote_schemas).for_each_concurrent(cfg.concurrency_limit, |remote_schema| {
+        let results = &results;
+        async move {
+            if let Some(name) = remote_schema["name"].as_str() {
+                let result = probe(cfg, &remote_schema["definition"]).await;
+                results.lock().unwrap().push((name.to_string(), result));
+            }
+        }
+    }).await;
+    let results = results.into_inner().unwrap();
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    let mut last_errors = probes.last_errors.lock().unwrap();
+    // Removed remote schemas disappear
+    last_errors.retain(|name, _| results.iter().any(|(probed, _)| probed == name));
+
+    let (mut up, mut durations) = (vec![], vec![]);
+    for (name, result) in results {
+        match result {
+            Ok(duration) => {
+                up.push((vec![name.clone()], 1.0));
+                durations.push((vec![name], duration.as_secs_f64()));
+            }
+            Err(e) => {
+                warn!("Remote schema {} failed the ping query: {}", name, e.message);
+                up.push((vec![name.clone()], 0.0));
+                last_errors.insert(name, (e.category, now));
+            }
+        }
+    }
+    metric_obj.REMOTE_SCHEMA_UP.set(up);
+    metric_obj.REMOTE_SCHEMA_PROBE_DURATION.set(durations);
+    metric_obj.REMOTE_SCHEMA_LAST_ERROR.set(last_errors.iter().map(|(name, (error, time))| (vec![name.clone(), error.to_string()], *time)).collect());
+}
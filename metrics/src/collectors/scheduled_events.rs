@@ -2,58 +2,73 @@ use super::sql::*;
 use crate::{Configuration, Telemetry};
 use log::{warn, info, debug};
 use crate::telemetry::MetricOption;
+use super::invocations::{check_invocations, InvocationKind};
+use super::incremental::{EventTable, HighWaterMarks};
+use super::catalog::{count_scheduled_events, CatalogSource};
+use serde_json::json;
 
-fn create_scheduled_event_request() -> SQLRequest {
-    SQLRequest {
-            request_type: "bulk".to_string(),
-            args: vec![
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'error';".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'delivered';".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'scheduled';".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: "run_sql".to_string(),
-                    args: RunSQLArgs {
-                        source: "default".to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'error' or status = 'delivered';".to_string()
-                    }
-                },
-            ],
+/// The queries of the bulk request.
+#[derive(Clone, Copy)]
+enum ScheduledEventQuery {
+    Failed,
+    Successful,
+    Pending,
+    Processed,
+    OldestPendingAge,
+}
+
+impl BulkQuery for ScheduledEventQuery {
+    const ALL: &'static [Self] = &[Self::Failed, Self::Successful, Self::Pending, Self::Processed, Self::OldestPendingAge];
+
+    fn counts_settled(self) -> bool {
+        matches!(self, Self::Failed | Self::Successful | Self::Processed)
+    }
+
+    fn sql(self, request_type: &str) -> String {
+        match self {
+            Self::Failed => "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'error';".to_string(),
+            Self::Successful => "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'delivered';".to_string(),
+            Self::Pending => "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'scheduled';".to_string(),
+            Self::Processed => "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'error' or status = 'delivered';".to_string(),
+            // Only overdue events are late, the others wait for their scheduled time
+            Self::OldestPendingAge => oldest_pending_age_sql(request_type, "hdb_catalog.hdb_scheduled_events", "scheduled_time", "status = 'scheduled' AND scheduled_time < NOW()", None),
         }
+    }
 }
 
-pub(crate) async fn check_scheduled_events(cfg: &Configuration,metric_obj: &Telemetry) {
+/// Counts the events with the metadata API, without the age and invocations only
+/// available with SQL.
+async fn check_scheduled_events_from_metadata(cfg: &Configuration, metric_obj: &Telemetry) -> Result<(), String> {
+    // Same order as ScheduledEventQuery
+    let counts = count_scheduled_events(cfg, json!({ "type": "one_off" }), &[&["error"], &["delivered"], &["scheduled"], &["error", "delivered"]]).await?;
+    let metrics = [&metric_obj.SCHEDULED_EVENTS_FAILED, &metric_obj.SCHEDULED_EVENTS_SUCCESSFUL, &metric_obj.SCHEDULED_EVENTS_PENDING, &metric_obj.SCHEDULED_EVENTS_PROCESSED];
+    for (metric, count) in metrics.iter().zip(counts) {
+        metric.set(count);
+    }
+    Ok(())
+}
+
+pub(crate) async fn check_scheduled_events(cfg: &Configuration,metric_obj: &Telemetry, catalog: Option<&CatalogSource>, marks: &HighWaterMarks) {
     if cfg.disabled_collectors.contains(&crate::Collectors::ScheduledEvents) {
         info!("Not collecting scheduled event.");
         return;
     }
+    let source = match catalog {
+        Some(CatalogSource::Sql(source)) => source.as_str(),
+        Some(CatalogSource::MetadataApi) => {
+            debug!("Running metadata API queries for scheduled events");
+            if let Err(e) = check_scheduled_events_from_metadata(cfg, metric_obj).await {
+                metric_obj.ERRORS_TOTAL.with_label_values(&["scheduled"]).inc();
+                warn!("Failed to collect scheduled event check {}", e);
+            }
+            return;
+        }
+        // The metadata database source could not be resolved yet
+        None => return,
+    };
     debug!("Running SQL query for scheduled events");
-    let sql_result = make_sql_request(&create_scheduled_event_request(), cfg).await;
+    let queries = bulk_queries::<ScheduledEventQuery>(cfg.incremental_counts);
+    let sql_result = make_sql_request(&bulk_request("run_sql", source, &queries), cfg).await;
     match sql_result {
         Ok(v) => {
 
@@ -61,20 +76,16 @@ pub(crate) async fn check_scheduled_events(cfg: &Configuration,metric_obj: &Tele
                 let response = v.json::<Vec<SQLResult>>().await;
                 match response {
                     Ok(v) => {
-                        v.iter().enumerate().for_each(|(index, query)| {
-                            let obj = match index as i32 {
-                                // Index values must match create_scheduled_event_request() for coherence
-                                0 => Ok((MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_FAILED), "failed scheduled triggers")),
-                                1 => Ok((MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_SUCCESSFUL), "successful scheduled triggers")),
-                                2 => Ok((MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_PENDING), "pending scheduled triggers")),
-                                3 => Ok((MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_PROCESSED), "processed scheduled triggers")),
-                                _ => {
-                                    warn!("Unexpected entry {:?}",query);
-                                    Err(format!("Unexpected entry {:?}",query))
-                                }
+                        queries.iter().zip(v.iter()).for_each(|(query, result)| {
+                            let obj = match query {
+                                ScheduledEventQuery::Failed => (MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_FAILED), "failed scheduled triggers"),
+                                ScheduledEventQuery::Successful => (MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_SUCCESSFUL), "successful scheduled triggers"),
+                                ScheduledEventQuery::Pending => (MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_PENDING), "pending scheduled triggers"),
+                                ScheduledEventQuery::Processed => (MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_PROCESSED), "processed scheduled triggers"),
+                                ScheduledEventQuery::OldestPendingAge => (MetricOption::IntGauge(&metric_obj.SCHEDULED_EVENTS_OLDEST_PENDING_AGE), "oldest overdue scheduled trigger age"),
                             };
 
-                            process_sql_result(query, obj, None);
+                            process_sql_result(result, obj, None);
                         });
                     }
                     Err(e) => {
@@ -95,4 +106,31 @@ pub(crate) async fn check_scheduled_events(cfg: &Configuration,metric_obj: &Tele
             warn!("Failed to collect scheduled event check {}", e);
         }
     };
+
+    if cfg.incremental_counts {
+        debug!("Running SQL query for settled scheduled events");
+        match marks.settled_events(cfg, EventTable::ScheduledEvents, source).await {
+            Ok(settled) => {
+                settled.delivered.iter().for_each(|(_, count)| metric_obj.SCHEDULED_EVENTS_SUCCESSFUL_TOTAL.inc_by(*count));
+                settled.failed.iter().for_each(|(_, count)| metric_obj.SCHEDULED_EVENTS_FAILED_TOTAL.inc_by(*count));
+            }
+            Err(e) => {
+                metric_obj.ERRORS_TOTAL.with_label_values(&["scheduled"]).inc();
+                warn!("Failed to collect settled scheduled events {}", e);
+            }
+        }
+    }
+
+    debug!("Running SQL query for scheduled event invocations");
+    match check_invocations(cfg, source, InvocationKind::ScheduledEvents, metric_obj.SCHEDULED_EVENTS_DELIVERY_LATENCY.buckets()).await {
+        Ok(stats) => {
+            metric_obj.SCHEDULED_EVENTS_DELIVERY_LATENCY.set(stats.latency);
+            metric_obj.SCHEDULED_EVENTS_INVOCATIONS.set(stats.invocations);
+            metric_obj.SCHEDULED_EVENTS_RETRIES.set(stats.retries);
+        }
+        Err(e) => {
+            metric_obj.ERRORS_TOTAL.with_label_values(&["scheduled"]).inc();
+            warn!("Failed to collect scheduled event invocations {}", e);
+        }
+    }
 }
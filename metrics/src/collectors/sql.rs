@@ -2,7 +2,8 @@ use log::{info};
 use reqwest::Response;
 use serde::{Deserialize, Serialize};
 use snafu::{prelude::*, Whatever};
-use crate::telemetry::MetricOption;
+use serde_json::Value;
+use crate::telemetry::{json_to_sample, unique_samples, MetricOption, Sample};
 
 #[derive(Serialize, Debug)]
 pub struct SQLRequest {
@@ -40,6 +41,47 @@ pub struct SQLResult {
     pub result: Option<Vec<SQLResultItem>>,
 }
 
+impl SQLResult {
+    /// The samples of the value and name rows, labeled by the name and the database
+    /// name if given. None if the query failed.
+    pub fn samples(&self, db_name: Option<&str>) -> Option<Vec<Sample>> {
+        match (self.result_type.as_str(), &self.result) {
+            ("TuplesOk", Some(rows)) => Some(rows.iter().skip(1).filter_map(|entry| match get_sql_entry_value(entry) {
+                SQLResultItem::IntStr(value, name) => Some((std::iter::once(name).chain(db_name.map(String::from)).collect(), value as f64)),
+                _ => None,
+            }).collect()),
+            _ => None,
+        }
+    }
+}
+
+/// A result with any columns, the first row names them.
+#[derive(Deserialize,Debug)]
+pub struct SQLRowsResult {
+    pub result_type: String,
+    pub result: Option<Vec<Vec<Value>>>,
+}
+
+impl SQLRowsResult {
+    /// A sample per row, with the value and label values of the named columns. Rows
+    /// with a NULL value have no sample, nor do rows repeating the label values of
+    /// an earlier row.
+    pub fn samples(self, value_column: &str, label_columns: &[&str]) -> Result<Vec<Sample>, String> {
+        let mut rows = match (self.result_type.as_str(), self.result) {
+            ("TuplesOk", Some(rows)) => rows.into_iter(),
+            (result_type, _) => return Err(format!("query returned {}", result_type)),
+        };
+        let columns = rows.next().unwrap_or_default();
+        let column = |name: &str| columns.iter().position(|c| c.as_str() == Some(name)).ok_or(format!("column {} missing", name));
+        let value_column = column(value_column)?;
+        let label_columns = label_columns.iter().map(|label| column(label)).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(unique_samples(rows.filter_map(|row| {
+            json_to_sample(row.get(value_column), label_columns.iter().map(|i| row.get(*i)))
+        })))
+    }
+}
+
 #[derive(Deserialize,Debug)]
 #[serde(untagged)]
 pub enum SQLResultItem {
@@ -49,8 +91,55 @@ pub enum SQLResultItem {
     Int(Vec<i64>)
 }
 
+/// A query of the bulk request of a collector, whose result is matched to the query
+/// rather than to its position.
+pub(crate) trait BulkQuery: Copy + 'static {
+    /// Every query, in the order of the bulk request
+    const ALL: &'static [Self];
+
+    /// Counts the settled events, which come from the high-water marks in incremental mode
+    fn counts_settled(self) -> bool;
+
+    fn sql(self, request_type: &str) -> String;
+}
+
+/// The queries to run, without the counts of settled events in incremental mode.
+pub(crate) fn bulk_queries<Q: BulkQuery>(incremental: bool) -> Vec<Q> {
+    Q::ALL.iter().copied().filter(|query| !(incremental && query.counts_settled())).collect()
+}
+
+/// The bulk request of the queries, their results come back in the same order.
+pub(crate) fn bulk_request<Q: BulkQuery>(request_type: &str, source: &str, queries: &[Q]) -> SQLRequest {
+    SQLRequest {
+        request_type: "bulk".to_string(),
+        args: queries.iter().map(|query| RunSQLQuery {
+            request_type: request_type.to_string(),
+            args: RunSQLArgs {
+                source: source.to_string(),
+                cascade: false,
+                read_only: true,
+                sql: query.sql(request_type),
+            },
+        }).collect(),
+    }
+}
+
+/// The age in seconds of the oldest pending row per group. Only the pending rows are
+/// read, so an index on the pending predicate serves the query: groups without pending
+/// rows have no row, and without a group the age is 0 if no row is pending.
+pub(crate) fn oldest_pending_age_sql(request_type: &str, table: &str, timestamp: &str, pending: &str, group: Option<&str>) -> String {
+    let age = match request_type {
+        "mssql_run_sql" => format!("DATEDIFF(second, MIN({timestamp}), SYSDATETIME())"),
+        _ => format!("EXTRACT(EPOCH FROM NOW() - MIN({timestamp}))::bigint"),
+    };
+    match group {
+        Some(group) => format!("SELECT {age} AS value, {group} FROM {table} WHERE {pending} GROUP BY {group};"),
+        None => format!("SELECT COALESCE({age}, 0) AS value FROM {table} WHERE {pending};"),
+    }
+}
+
 pub(crate) async fn make_sql_request(request: &SQLRequest, cfg: &crate::Configuration) -> Result<Response, Whatever> {
-    let admin_secret = match &cfg.hasura_admin {
+    let admin_secret = match cfg.hasura_admin.as_deref() {
         Some(v) => Ok(v),
         None => {
             whatever!("Metadata should be collected, but admin secret missing!")
@@ -74,23 +163,16 @@ pub(crate) fn get_sql_entry_value(entry: &SQLResultItem ) -> SQLResultItem {
             SQLResultItem::IntStr(*value, trigger_name.to_string())
         }
         SQLResultItem::StrStr(count,trigger_name) => {
-            let value = match count.trim().parse::<i64>() {
-                Ok(value) => value,
-                _ => 0,
-            };
+            let value = count.trim().parse::<i64>().unwrap_or_default();
             SQLResultItem::IntStr(value,trigger_name.to_string())
         }
         SQLResultItem::Str(vect) => {
-            let parsed_count;
-            if vect.len() == 1 {
-                parsed_count = match vect[0].trim().parse::<i64>() {
-                    Ok(value) => value,
-                    Err(_) => 0,
-                };
+            let parsed_count = if vect.len() == 1 {
+                vect[0].trim().parse::<i64>().unwrap_or_default()
             } else {
                 print!("Expected one value in array '{:?}'",vect);
-                parsed_count = 0;
-            }
+                0
+            };
 
             SQLResultItem::IntStr(parsed_count,"".to_string())
         }
@@ -111,35 +193,33 @@ pub(crate) fn get_sql_entry_value(entry: &SQLResultItem ) -> SQLResultItem {
     }
 }
 
-pub(crate) fn process_sql_result<T>(query: &SQLResult, obj: Result<(MetricOption,&str),T>, db_name_opt: Option<&str>) {
-    if let Ok((metric, metric_name)) = obj {
-        if query.result_type == String::from("TuplesOk") {
-            query.result.as_ref().unwrap().iter().skip(1).for_each(|entry| {
-                let (value, trigger_name) = if let SQLResultItem::IntStr(value, trigger_name) = get_sql_entry_value(entry) {
-                    (value, trigger_name)
-                } else {
-                    (0,"".to_string())
-                };
-
-                match metric {
-                    MetricOption::IntGaugeVec(metric) => {
-                        if let Some(db_name) = db_name_opt {
-                            metric.with_label_values( & [trigger_name.as_str(), db_name]).set(value);
-                        } else {
-                            metric.with_label_values( & [trigger_name.as_str()]).set(value);
-                        }
-                    }
-                    MetricOption::IntGauge(metric) => {
-                        metric.set(value)
+pub(crate) fn process_sql_result(query: &SQLResult, (metric, metric_name): (MetricOption,&str), db_name_opt: Option<&str>) {
+    if query.result_type == "TuplesOk" {
+        query.result.as_ref().unwrap().iter().skip(1).for_each(|entry| {
+            let (value, trigger_name) = if let SQLResultItem::IntStr(value, trigger_name) = get_sql_entry_value(entry) {
+                (value, trigger_name)
+            } else {
+                (0,"".to_string())
+            };
+
+            match metric {
+                MetricOption::IntGaugeVec(metric) => {
+                    if let Some(db_name) = db_name_opt {
+                        metric.with_label_values( & [trigger_name.as_str(), db_name]).set(value);
+                    } else {
+                        metric.with_label_values( & [trigger_name.as_str()]).set(value);
                     }
                 }
-            });
-        } else {
-            if let Some(db_name) = db_name_opt {
-                info!("Result of SQL query for '{}' on database {} has failed or is empty: {:?}",metric_name,db_name.to_string(),query);
-            } else {
-                info!("Result of SQL query for '{}' has failed or is empty: {:?}",metric_name,query);
+                MetricOption::IntGauge(metric) => {
+                    metric.set(value)
+                }
             }
+        });
+    } else {
+        if let Some(db_name) = db_name_opt {
+            info!("Result of SQL query for '{}' on database {} has failed or is empty: {:?}",metric_name,db_name.to_string(),query);
+        } else {
+            info!("Result of SQL query for '{}' has failed or is empty: {:?}",metric_name,query);
         }
     }
 }
@@ -164,4 +244,62 @@ pub(crate) fn process_sql_result<T>(query: &SQLResult, obj: Result<(MetricOption
 //         return get_sql_entry_value(entry);
 //     }
 //     None
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rows(result: Value) -> SQLRowsResult {
+        serde_json::from_value(json!({ "result_type": "TuplesOk", "result": result })).unwrap()
+    }
+
+    #[test]
+    fn samples_of_named_columns() {
+        let result = rows(json!([["status", "value", "name"], ["ok", "2", "a"], ["failed", 3, null], [true, "x", "c"], ["ok", null, "d"]]));
+        let samples = result.samples("value", &["name", "status"]).unwrap();
+        assert_eq!(samples, vec![
+            (vec!["".to_string(), "failed".to_string()], 3.0),
+            (vec!["a".to_string(), "ok".to_string()], 2.0),
+        ]);
+    }
+
+    #[test]
+    fn samples_keep_the_first_of_duplicate_label_values() {
+        let result = rows(json!([["name", "value"], ["a", "1"], ["a", "2"], ["b", "3"]]));
+        let samples = result.samples("value", &["name"]).unwrap();
+        assert_eq!(samples, vec![(vec!["a".to_string()], 1.0), (vec!["b".to_string()], 3.0)]);
+    }
+
+    #[test]
+    fn samples_of_value_and_name_rows() {
+        let result: SQLResult = serde_json::from_value(json!({ "result_type": "TuplesOk", "result": [["value", "trigger_name"], ["12", "a"], [3, "b"]] })).unwrap();
+        assert_eq!(result.samples(Some("default")).unwrap(), vec![
+            (vec!["a".to_string(), "default".to_string()], 12.0),
+            (vec!["b".to_string(), "default".to_string()], 3.0),
+        ]);
+        assert_eq!(result.samples(None).unwrap()[0], (vec!["a".to_string()], 12.0));
+
+        let failed: SQLResult = serde_json::from_value(json!({ "result_type": "CommandOk", "result": null })).unwrap();
+        assert_eq!(failed.samples(None), None);
+    }
+
+    #[test]
+    fn oldest_pending_age_reads_only_pending_rows() {
+        assert_eq!(
+            oldest_pending_age_sql("run_sql", "t", "created_at", "pending", Some("name")),
+            "SELECT EXTRACT(EPOCH FROM NOW() - MIN(created_at))::bigint AS value, name FROM t WHERE pending GROUP BY name;",
+        );
+        assert_eq!(
+            oldest_pending_age_sql("mssql_run_sql", "t", "created_at", "pending", None),
+            "SELECT COALESCE(DATEDIFF(second, MIN(created_at), SYSDATETIME()), 0) AS value FROM t WHERE pending;",
+        );
+    }
+
+    #[test]
+    fn samples_of_failed_query_or_missing_column() {
+        let failed: SQLRowsResult = serde_json::from_value(json!({ "result_type": "CommandOk", "result": null })).unwrap();
+        assert_eq!(failed.samples("value", &[]), Err("query returned CommandOk".to_string()));
+        assert_eq!(rows(json!([["name"], ["a"]])).samples("value", &["name"]), Err("column value missing".to_string()));
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::{Bucket, LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, ProtobufEncoder, TextEncoder};
+use protobuf::Message;
+
+use crate::telemetry::{Exemplar, HistogramExemplars};
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Field numbers of fields in io.prometheus.client, which are newer than the
+// proto model shipped with the prometheus crate.
+const COUNTER_CREATED_TIMESTAMP: u32 = 3;
+const HISTOGRAM_CREATED_TIMESTAMP: u32 = 15;
+const BUCKET_EXEMPLAR: u32 = 3;
+const HISTOGRAM_EXEMPLARS: u32 = 16;
+
+const UNITS: [&str; 5] = ["seconds", "bytes", "ratio", "celsius", "meters"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    OpenMetrics,
+    Protobuf,
+}
+
+impl Format {
+    /// Picks the best supported format for an `Accept` header, honouring the
+    /// quality values of the offered media types. Falls back to the classic text
+    /// format if nothing matches.
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let mut best: Option<(Format, f32)> = None;
+
+        for entry in accept.unwrap_or_default().split(',') {
+            let mut parts = entry.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let mut quality = 1.0;
+            let mut params = HashMap::new();
+            for param in parts {
+                if let Some((key, value)) = param.split_once('=') {
+                    let key = key.trim().to_ascii_lowercase();
+                    let value = value.trim().trim_matches('"').to_string();
+                    if key == "q" {
+                        quality = value.parse().unwrap_or(0.0);
+                    } else {
+                        params.insert(key, value);
+                    }
+                }
+            }
+
+            let format = match media_type.as_str() {
+                "application/vnd.google.protobuf" => {
+                    let proto = params.get("proto").map(String::as_str);
+                    let encoding = params.get("encoding").map(String::as_str);
+                    if proto == Some("io.prometheus.client.MetricFamily") && encoding == Some("delimited") {
+                        Format::Protobuf
+                    } else {
+                        continue;
+                    }
+                }
+                "application/openmetrics-text" => Format::OpenMetrics,
+                "text/plain" | "text/*" | "*/*" => Format::Text,
+                _ => continue,
+            };
+
+            if quality > 0.0 && best.map_or(true, |(_, q)| quality > q) {
+                best = Some((format, quality));
+            }
+        }
+
+        best.map_or(Format::Text, |(format, _)| format)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Text => prometheus::TEXT_FORMAT,
+            Format::OpenMetrics => OPENMETRICS_FORMAT,
+            Format::Protobuf => prometheus::PROTOBUF_FORMAT,
+        }
+    }
+}
+
+/// Remembers when a series was first exposed, which is used for the `_created`
+/// samples. Series are created lazily by the log processor, so the time of the
+/// previous scrape is the latest point in time the series is known not to have
+/// existed yet. Each route exposing its own series needs its own tracker.
+#[derive(Debug)]
+pub struct SeriesCreated {
+    state: Mutex<(f64, HashMap<String, f64>)>,
+}
+
+impl SeriesCreated {
+    pub fn new() -> Self {
+        SeriesCreated {
+            state: Mutex::new((now(), HashMap::new())),
+        }
+    }
+
+    fn stamp(&self, families: &[MetricFamily]) -> HashMap<String, f64> {
+        let mut state = self.state.lock().unwrap();
+        let (last_scrape, known) = &mut *state;
+
+        let mut created = HashMap::new();
+        for family in families {
+            if !matches!(family.get_field_type(), MetricType::COUNTER | MetricType::HISTOGRAM | MetricType::SUMMARY) {
+                continue;
+            }
+            for metric in family.get_metric() {
+                let key = series_key(family.get_name(), metric.get_label());
+                let timestamp = *known.entry(key.clone()).or_insert(*last_scrape);
+                created.insert(key, timestamp);
+            }
+        }
+        // Removed series are forgotten, they are created anew if they come back
+        known.retain(|key, _| created.contains_key(key));
+        *last_scrape = now();
+
+        created
+    }
+}
+
+pub fn encode(format: Format, metric_families: &mut [MetricFamily], created: &SeriesCreated, exemplars: &[&HistogramExemplars]) -> prometheus::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    match format {
+        Format::Text => TextEncoder::new().encode(metric_families, &mut buffer)?,
+        Format::OpenMetrics => {
+            let created = created.stamp(metric_families);
+            buffer = encode_openmetrics(metric_families, &created, exemplars).into_bytes();
+        }
+        Format::Protobuf => {
+            let created = created.stamp(metric_families);
+            add_created_timestamps(metric_families, &created);
+            add_exemplars(metric_families, exemplars);
+            ProtobufEncoder::new().encode(metric_families, &mut buffer)?;
+        }
+    }
+    Ok(buffer)
+}
+
+fn encode_openmetrics(metric_families: &[MetricFamily], created: &HashMap<String, f64>, exemplars: &[&HistogramExemplars]) -> String {
+    let mut out = String::new();
+
+    // Counters lose their `_total` suffix in OpenMetrics, so e.g. `x_total` and a
+    // labeled `x` end up with the same name. Those are merged into a single family.
+    let mut groups: Vec<(&str, MetricType, Vec<&MetricFamily>)> = Vec::new();
+    for family in metric_families.iter().filter(|family| !family.get_metric().is_empty()) {
+        let metric_type = family.get_field_type();
+        let name = match metric_type {
+            MetricType::COUNTER => family.get_name().strip_suffix("_total").unwrap_or(family.get_name()),
+            _ => family.get_name(),
+        };
+        match groups.iter_mut().find(|(n, t, _)| *n == name && *t == metric_type) {
+            Some((_, _, families)) => families.push(family),
+            None => groups.push((name, metric_type, vec![family])),
+        }
+    }
+
+    for (name, metric_type, families) in groups {
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+
+        let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+        if let Some(unit) = UNITS.iter().find(|unit| name.ends_with(&format!("_{}", unit))) {
+            let _ = writeln!(out, "# UNIT {} {}", name, unit);
+        }
+        let _ = writeln!(out, "# HELP {} {}", name, escape(families[0].get_help()));
+
+        for (family, metric) in families.iter().flat_map(|family| family.get_metric().iter().map(move |metric| (family, metric))) {
+            let created = created.get(&series_key(family.get_name(), metric.get_label()));
+            match metric_type {
+                MetricType::COUNTER => {
+                    write_sample(&mut out, name, "_total", metric, None, metric.get_counter().get_value(), None);
+                    write_created(&mut out, name, metric, created);
+                }
+                MetricType::GAUGE => {
+                    write_sample(&mut out, name, "", metric, None, metric.get_gauge().get_value(), None);
+                }
+                MetricType::UNTYPED => {
+                    write_sample(&mut out, name, "", metric, None, metric.get_untyped().get_value(), None);
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket_exemplars = exemplars.iter()
+                        .find(|e| e.name == family.get_name())
+                        .map(|e| e.get(metric.get_label()))
+                        .unwrap_or_default();
+                    let mut inf_written = false;
+                    for (index, bucket) in histogram.get_bucket().iter().enumerate() {
+                        let upper_bound = bucket.get_upper_bound();
+                        inf_written |= upper_bound == f64::INFINITY;
+                        write_sample(&mut out, name, "_bucket", metric, Some(("le", upper_bound)), bucket.get_cumulative_count() as f64, bucket_exemplars.get(&index));
+                    }
+                    if !inf_written {
+                        let exemplar = bucket_exemplars.get(&histogram.get_bucket().len());
+                        write_sample(&mut out, name, "_bucket", metric, Some(("le", f64::INFINITY)), histogram.get_sample_count() as f64, exemplar);
+                    }
+                    write_sample(&mut out, name, "_count", metric, None, histogram.get_sample_count() as f64, None);
+                    write_sample(&mut out, name, "_sum", metric, None, histogram.get_sample_sum(), None);
+                    write_created(&mut out, name, metric, created);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        write_sample(&mut out, name, "", metric, Some(("quantile", quantile.get_quantile())), quantile.get_value(), None);
+                    }
+                    write_sample(&mut out, name, "_count", metric, None, summary.get_sample_count() as f64, None);
+                    write_sample(&mut out, name, "_sum", metric, None, summary.get_sample_sum(), None);
+                    write_created(&mut out, name, metric, created);
+                }
+            }
+        }
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+fn write_sample(out: &mut String, name: &str, suffix: &str, metric: &Metric, extra_label: Option<(&str, f64)>, value: f64, exemplar: Option<&Exemplar>) {
+    out.push_str(name);
+    out.push_str(suffix);
+
+    let mut labels: Vec<String> = metric
+        .get_label()
+        .iter()
+        .map(|label| format!("{}=\"{}\"", label.get_name(), escape(label.get_value())))
+        .collect();
+    if let Some((label_name, label_value)) = extra_label {
+        labels.push(format!("{}=\"{}\"", label_name, format_canonical_float(label_value)));
+    }
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+
+    let _ = write!(out, " {}", format_float(value));
+    if metric.has_timestamp_ms() {
+        let _ = write!(out, " {}", metric.get_timestamp_ms() as f64 / 1000.0);
+    }
+    if let Some(exemplar) = exemplar {
+        let labels: Vec<String> = exemplar.labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        let _ = write!(out, " # {{{}}} {} {}", labels.join(","), format_float(exemplar.value), exemplar.timestamp);
+    }
+    out.push('\n');
+}
+
+fn write_created(out: &mut String, name: &str, metric: &Metric, created: Option<&f64>) {
+    if let Some(created) = created {
+        write_sample(out, name, "_created", metric, None, *created, None);
+    }
+}
+
+fn add_created_timestamps(metric_families: &mut [MetricFamily], created: &HashMap<String, f64>) {
+    for family in metric_families.iter_mut() {
+        let metric_type = family.get_field_type();
+        let family_name = family.get_name().to_string();
+        for metric in family.mut_metric().iter_mut() {
+            let timestamp = match created.get(&series_key(&family_name, metric.get_label())) {
+                Some(timestamp) => timestamp_bytes(*timestamp),
+                None => continue,
+            };
+            match metric_type {
+                MetricType::COUNTER => metric.mut_counter().mut_unknown_fields().add_length_delimited(COUNTER_CREATED_TIMESTAMP, timestamp),
+                MetricType::HISTOGRAM => metric.mut_histogram().mut_unknown_fields().add_length_delimited(HISTOGRAM_CREATED_TIMESTAMP, timestamp),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn add_exemplars(metric_families: &mut [MetricFamily], exemplars: &[&HistogramExemplars]) {
+    for family in metric_families.iter_mut() {
+        let histogram_exemplars = match exemplars.iter().find(|e| e.name == family.get_name()) {
+            Some(histogram_exemplars) => histogram_exemplars,
+            None => continue,
+        };
+        for metric in family.mut_metric().iter_mut() {
+            let bucket_exemplars = histogram_exemplars.get(metric.get_label());
+            let histogram = metric.mut_histogram();
+            if histogram_exemplars.native {
+                for exemplar in bucket_exemplars.values() {
+                    histogram.mut_unknown_fields().add_length_delimited(HISTOGRAM_EXEMPLARS, exemplar_bytes(exemplar));
+                }
+                continue;
+            }
+            for (index, exemplar) in bucket_exemplars {
+                if index == histogram.get_bucket().len() {
+                    // The +Inf bucket is implicit in the protobuf format, it needs to be
+                    // present to carry an exemplar.
+                    let mut bucket = Bucket::default();
+                    bucket.set_upper_bound(f64::INFINITY);
+                    bucket.set_cumulative_count(histogram.get_sample_count());
+                    histogram.mut_bucket().push(bucket);
+                }
+                if let Some(bucket) = histogram.mut_bucket().get_mut(index) {
+                    bucket.mut_unknown_fields().add_length_delimited(BUCKET_EXEMPLAR, exemplar_bytes(&exemplar));
+                }
+            }
+        }
+    }
+}
+
+/// Serializes an `io.prometheus.client.Exemplar`.
+pub fn exemplar_bytes(exemplar: &Exemplar) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut os = protobuf::CodedOutputStream::vec(&mut bytes);
+    for (name, value) in &exemplar.labels {
+        let mut label = LabelPair::default();
+        label.set_name(name.clone());
+        label.set_value(value.clone());
+        let _ = os.write_bytes(1, &label.write_to_bytes().unwrap_or_default());
+    }
+    let _ = os.write_double(2, exemplar.value);
+    let _ = os.write_bytes(3, &timestamp_bytes(exemplar.timestamp));
+    let _ = os.flush();
+    drop(os);
+    bytes
+}
+
+/// Serializes a `google.protobuf.Timestamp` for the given unix time in seconds.
+pub fn timestamp_bytes(seconds: f64) -> Vec<u8> {
+    let mut timestamp = protobuf::well_known_types::Timestamp::new();
+    timestamp.set_seconds(seconds.trunc() as i64);
+    timestamp.set_nanos((seconds.fract() * 1e9) as i32);
+    timestamp.write_to_bytes().unwrap_or_default()
+}
+
+fn series_key(name: &str, labels: &[LabelPair]) -> String {
+    let mut key = name.to_string();
+    for label in labels {
+        let _ = write!(key, "\u{0}{}={}", label.get_name(), label.get_value());
+    }
+    key
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        format!("{}", value)
+    }
+}
+
+/// Label values holding numbers (`le`, `quantile`) must always carry a fraction
+/// in OpenMetrics, so `1` is written as `1.0`.
+fn format_canonical_float(value: f64) -> String {
+    let formatted = format_float(value);
+    if value.is_finite() && value.fract() == 0.0 && !formatted.contains('e') {
+        format!("{}.0", formatted)
+    } else {
+        formatted
+    }
+}
+
+pub fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{IntCounter, IntCounterVec, Opts, Registry};
+
+    fn gather(counters: &[(&str, &[&str])]) -> Vec<MetricFamily> {
+        let registry = Registry::new();
+        for (name, labels) in counters {
+            if labels.is_empty() {
+                let counter = IntCounter::new(*name, "Requests").unwrap();
+                counter.inc();
+                registry.register(Box::new(counter)).unwrap();
+            } else {
+                let counter = IntCounterVec::new(Opts::new(*name, "Requests"), &["status"]).unwrap();
+                for label in labels.iter() {
+                    counter.with_label_values(&[label]).inc();
+                }
+                registry.register(Box::new(counter)).unwrap();
+            }
+        }
+        registry.gather()
+    }
+
+    #[test]
+    fn negotiate_honours_quality() {
+        assert_eq!(Format::negotiate(None), Format::Text);
+        assert_eq!(Format::negotiate(Some("application/json")), Format::Text);
+        assert_eq!(Format::negotiate(Some("application/openmetrics-text; version=1.0.0")), Format::OpenMetrics);
+        assert_eq!(Format::negotiate(Some("application/openmetrics-text;q=0.5, text/plain;q=0.9")), Format::Text);
+        assert_eq!(Format::negotiate(Some("text/plain;q=0.5, application/openmetrics-text")), Format::OpenMetrics);
+        assert_eq!(Format::negotiate(Some("application/openmetrics-text;q=0")), Format::Text);
+    }
+
+    #[test]
+    fn negotiate_requires_delimited_protobuf() {
+        let protobuf = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited";
+        assert_eq!(Format::negotiate(Some(protobuf)), Format::Protobuf);
+        assert_eq!(Format::negotiate(Some("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text")), Format::Text);
+        assert_eq!(Format::negotiate(Some(&format!("{};q=0.7,application/openmetrics-text;q=0.8", protobuf))), Format::OpenMetrics);
+    }
+
+    #[test]
+    fn openmetrics_counters_keep_total_on_samples_only() {
+        let families = gather(&[("requests_total", &[])]);
+        let created = HashMap::from([(series_key("requests_total", &[]), 12.5)]);
+        assert_eq!(
+            encode_openmetrics(&families, &created, &[]),
+            "# TYPE requests counter\n# HELP requests Requests\nrequests_total 1\nrequests_created 12.5\n# EOF\n",
+        );
+    }
+
+    #[test]
+    fn openmetrics_merges_counters_losing_their_suffix() {
+        let families = gather(&[("requests", &["200"]), ("requests_total", &[])]);
+        let encoded = encode_openmetrics(&families, &HashMap::new(), &[]);
+        assert_eq!(encoded.matches("# TYPE requests counter").count(), 1);
+        assert!(encoded.contains("requests_total{status=\"200\"} 1\n"));
+        assert!(encoded.contains("requests_total 1\n"));
+    }
+
+    #[test]
+    fn openmetrics_exemplar_follows_the_sample() {
+        let exemplar = Exemplar {
+            labels: vec![("trace_id".to_string(), "abc".to_string())],
+            value: 0.3,
+            timestamp: 1700000000.5,
+        };
+        let mut out = String::new();
+        write_sample(&mut out, "latency_seconds", "_bucket", &Metric::default(), Some(("le", 1.0)), 2.0, Some(&exemplar));
+        assert_eq!(out, "latency_seconds_bucket{le=\"1.0\"} 2 # {trace_id=\"abc\"} 0.3 1700000000.5\n");
+    }
+
+    #[test]
+    fn series_created_keeps_the_first_time_and_forgets_removed_series() {
+        let created = SeriesCreated::new();
+        let key = series_key("requests_total", &[]);
+        let first = created.stamp(&gather(&[("requests_total", &[])]))[&key];
+        assert_eq!(created.stamp(&gather(&[("requests_total", &[])]))[&key], first);
+
+        assert!(created.stamp(&[]).is_empty());
+        assert!(created.stamp(&gather(&[("requests_total", &[])]))[&key] > first);
+    }
+}
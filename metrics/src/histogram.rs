@@ -0,0 +1,359 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Histogram, LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{HistogramOpts, HistogramVec, Opts};
+use protobuf::Message;
+
+// Native histogram fields of io.prometheus.client.Histogram, which are not part of
+// the proto model shipped with the prometheus crate.
+const SCHEMA: u32 = 5;
+const ZERO_THRESHOLD: u32 = 6;
+const ZERO_COUNT: u32 = 7;
+const NEGATIVE_SPAN: u32 = 9;
+const NEGATIVE_DELTA: u32 = 10;
+const POSITIVE_SPAN: u32 = 12;
+const POSITIVE_DELTA: u32 = 13;
+
+const MIN_SCHEMA: i32 = -4;
+const MAX_SCHEMA: i32 = 8;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramType {
+    Classic,
+    Native,
+}
+
+#[derive(Clone, Debug)]
+pub struct HistogramConfig {
+    pub histogram_type: HistogramType,
+    pub buckets: Vec<f64>,
+    pub metric_buckets: HashMap<String, Vec<f64>>,
+    pub native_schema: i32,
+    pub native_zero_threshold: f64,
+    pub native_max_buckets: usize,
+}
+
+impl HistogramConfig {
+    /// The classic buckets of a histogram, the per metric configuration takes
+    /// precedence over the global buckets.
+    pub fn buckets_for(&self, name: &str) -> Vec<f64> {
+        match self.metric_buckets.get(name) {
+            Some(buckets) => buckets.clone(),
+            None if self.buckets.is_empty() => prometheus::DEFAULT_BUCKETS.to_vec(),
+            None => self.buckets.clone(),
+        }
+    }
+
+    /// The buckets of the delivery latencies of events, which span seconds to hours
+    /// rather than the request times of the global buckets, so only the per metric
+    /// configuration takes precedence.
+    pub fn latency_buckets_for(&self, name: &str) -> Vec<f64> {
+        match self.metric_buckets.get(name) {
+            Some(buckets) => buckets.clone(),
+            None => vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum HistogramMetric {
+    Classic(HistogramVec),
+    Native(NativeHistogramVec),
+}
+
+impl HistogramMetric {
+    pub fn new(opts: Opts, label_names: &[&str], config: &HistogramConfig) -> prometheus::Result<Self> {
+        match config.histogram_type {
+            HistogramType::Classic => {
+                let buckets = config.buckets_for(&opts.fq_name());
+                Ok(HistogramMetric::Classic(HistogramVec::new(HistogramOpts { common_opts: opts, buckets }, label_names)?))
+            }
+            HistogramType::Native => Ok(HistogramMetric::Native(NativeHistogramVec::new(opts, label_names, config)?)),
+        }
+    }
+
+    pub fn observe(&self, label_values: &[&str], value: f64) {
+        match self {
+            HistogramMetric::Classic(histogram) => histogram.with_label_values(label_values).observe(value),
+            HistogramMetric::Native(histogram) => histogram.observe(label_values, value),
+        }
+    }
+
+    /// The classic bucket boundaries, native histograms have none.
+    pub fn buckets(&self, config: &HistogramConfig, name: &str) -> Vec<f64> {
+        match self {
+            HistogramMetric::Classic(_) => config.buckets_for(name),
+            HistogramMetric::Native(_) => vec![],
+        }
+    }
+
+    pub fn is_native(&self) -> bool {
+        matches!(self, HistogramMetric::Native(_))
+    }
+}
+
+impl Collector for HistogramMetric {
+    fn desc(&self) -> Vec<&Desc> {
+        match self {
+            HistogramMetric::Classic(histogram) => histogram.desc(),
+            HistogramMetric::Native(histogram) => histogram.desc(),
+        }
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        match self {
+            HistogramMetric::Classic(histogram) => histogram.collect(),
+            HistogramMetric::Native(histogram) => histogram.collect(),
+        }
+    }
+}
+
+/// A Prometheus native histogram with exponential buckets. The prometheus crate
+/// does not support them, so the sparse bucket fields are added to the exposed
+/// protobuf as unknown fields. Text formats only show the count and sum.
+#[derive(Clone, Debug)]
+pub struct NativeHistogramVec {
+    desc: Desc,
+    schema: i32,
+    zero_threshold: f64,
+    max_buckets: usize,
+    series: Arc<Mutex<HashMap<Vec<String>, NativeHistogram>>>,
+}
+
+/// The state of one native histogram series, buckets are keyed by their index.
+#[derive(Clone, Debug, Default)]
+pub struct NativeHistogram {
+    pub schema: i32,
+    pub count: u64,
+    pub sum: f64,
+    pub zero_count: u64,
+    pub positive: BTreeMap<i32, u64>,
+    pub negative: BTreeMap<i32, u64>,
+}
+
+impl NativeHistogramVec {
+    pub fn new(opts: Opts, label_names: &[&str], config: &HistogramConfig) -> prometheus::Result<Self> {
+        let desc = Desc::new(
+            opts.fq_name(),
+            opts.help.clone(),
+            label_names.iter().map(|v| v.to_string()).collect(),
+            opts.const_labels.clone(),
+        )?;
+        Ok(NativeHistogramVec {
+            desc,
+            schema: config.native_schema.clamp(MIN_SCHEMA, MAX_SCHEMA),
+            zero_threshold: config.native_zero_threshold,
+            max_buckets: config.native_max_buckets,
+            series: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn observe(&self, label_values: &[&str], value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        let mut series = self.series.lock().unwrap();
+        let histogram = series.entry(key).or_insert_with(|| NativeHistogram {
+            schema: self.schema,
+            ..Default::default()
+        });
+
+        histogram.count += 1;
+        histogram.sum += value;
+        if value.abs() <= self.zero_threshold {
+            histogram.zero_count += 1;
+        } else if value > 0.0 {
+            *histogram.positive.entry(bucket_index(value, histogram.schema)).or_default() += 1;
+        } else {
+            *histogram.negative.entry(bucket_index(-value, histogram.schema)).or_default() += 1;
+        }
+
+        // Like the Go client, trade resolution for a bounded number of buckets.
+        while self.max_buckets > 0
+            && histogram.positive.len() + histogram.negative.len() > self.max_buckets
+            && histogram.schema > MIN_SCHEMA
+        {
+            histogram.schema -= 1;
+            histogram.positive = halve_resolution(&histogram.positive);
+            histogram.negative = halve_resolution(&histogram.negative);
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.desc.fq_name
+    }
+
+    pub fn zero_threshold(&self) -> f64 {
+        self.zero_threshold
+    }
+
+    /// The state of the series with the given labels.
+    pub fn get(&self, labels: &[LabelPair]) -> Option<NativeHistogram> {
+        let key: Vec<String> = self.desc.variable_labels.iter()
+            .map(|name| labels.iter().find(|l| l.get_name() == name).map_or("".to_string(), |l| l.get_value().to_string()))
+            .collect();
+        self.series.lock().unwrap().get(&key).cloned()
+    }
+
+    fn to_metric(&self, label_values: &[String], histogram: &NativeHistogram) -> Metric {
+        let mut labels: Vec<LabelPair> = self.desc.const_label_pairs.clone();
+        for (name, value) in self.desc.variable_labels.iter().zip(label_values) {
+            let mut label = LabelPair::default();
+            label.set_name(name.clone());
+            label.set_value(value.clone());
+            labels.push(label);
+        }
+        labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        let mut proto = Histogram::default();
+        proto.set_sample_count(histogram.count);
+        proto.set_sample_sum(histogram.sum);
+
+        let fields = proto.mut_unknown_fields();
+        fields.add_varint(SCHEMA, zigzag32(histogram.schema));
+        fields.add_fixed64(ZERO_THRESHOLD, self.zero_threshold.to_bits());
+        fields.add_varint(ZERO_COUNT, histogram.zero_count);
+        for (buckets, span_field, delta_field) in [
+            (&histogram.negative, NEGATIVE_SPAN, NEGATIVE_DELTA),
+            (&histogram.positive, POSITIVE_SPAN, POSITIVE_DELTA),
+        ] {
+            let (spans, deltas) = spans_and_deltas(buckets);
+            for (offset, length) in spans {
+                fields.add_length_delimited(span_field, span_bytes(offset, length));
+            }
+            for delta in deltas {
+                fields.add_varint(delta_field, zigzag64(delta));
+            }
+        }
+        // Without any bucket or zero threshold the histogram would be taken for
+        // a classic one, an empty span marks it as native.
+        if histogram.positive.is_empty() && histogram.negative.is_empty() && self.zero_threshold == 0.0 && histogram.zero_count == 0 {
+            fields.add_length_delimited(POSITIVE_SPAN, span_bytes(0, 0));
+        }
+
+        let mut metric = Metric::default();
+        metric.set_label(labels.into());
+        metric.set_histogram(proto);
+        metric
+    }
+}
+
+impl Collector for NativeHistogramVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let series = self.series.lock().unwrap();
+        let mut metrics: Vec<Metric> = series
+            .iter()
+            .map(|(label_values, histogram)| self.to_metric(label_values, histogram))
+            .collect();
+        metrics.sort_by_cached_key(|m| m.get_label().iter().map(|l| l.get_value().to_string()).collect::<Vec<_>>());
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+/// Index of the bucket `(base^(i-1), base^i]` holding the value, with
+/// `base = 2^(2^-schema)`.
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    (value.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+fn halve_resolution(buckets: &BTreeMap<i32, u64>) -> BTreeMap<i32, u64> {
+    let mut merged = BTreeMap::new();
+    for (index, count) in buckets {
+        *merged.entry((index + 1).div_euclid(2)).or_default() += count;
+    }
+    merged
+}
+
+/// The spans of consecutive buckets and the deltas between their counts, as used
+/// by the sparse native histogram encodings.
+pub fn spans_and_deltas(buckets: &BTreeMap<i32, u64>) -> (Vec<(i32, u32)>, Vec<i64>) {
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut deltas = Vec::new();
+    let mut previous: Option<(i32, u64)> = None;
+
+    for (&index, &count) in buckets {
+        match previous {
+            Some((previous_index, _)) if index == previous_index + 1 => {
+                if let Some(span) = spans.last_mut() {
+                    span.1 += 1;
+                }
+            }
+            Some((previous_index, _)) => spans.push((index - previous_index - 1, 1)),
+            None => spans.push((index, 1)),
+        }
+        deltas.push(count as i64 - previous.map_or(0, |(_, c)| c as i64));
+        previous = Some((index, count));
+    }
+
+    (spans, deltas)
+}
+
+fn span_bytes(offset: i32, length: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut os = protobuf::CodedOutputStream::vec(&mut bytes);
+    let _ = os.write_sint32(1, offset);
+    let _ = os.write_uint32(2, length);
+    let _ = os.flush();
+    drop(os);
+    bytes
+}
+
+fn zigzag32(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn zigzag64(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_index_includes_the_upper_bound() {
+        assert_eq!(bucket_index(1.0, 0), 0);
+        assert_eq!(bucket_index(1.5, 0), 1);
+        assert_eq!(bucket_index(2.0, 0), 1);
+        assert_eq!(bucket_index(0.5, 0), -1);
+        assert_eq!(bucket_index(0.3, 0), -1);
+        // base sqrt(2)
+        assert_eq!(bucket_index(1.5, 1), 2);
+        assert_eq!(bucket_index(2.0, 1), 2);
+        assert_eq!(bucket_index(2.5, 1), 3);
+    }
+
+    #[test]
+    fn halve_resolution_merges_pairs_of_buckets() {
+        let buckets = BTreeMap::from([(-1, 1), (0, 2), (1, 3), (2, 4), (3, 5)]);
+        assert_eq!(halve_resolution(&buckets), BTreeMap::from([(0, 3), (1, 7), (2, 5)]));
+    }
+
+    #[test]
+    fn spans_and_deltas_of_sparse_buckets() {
+        let buckets = BTreeMap::from([(-2, 2), (-1, 3), (2, 1), (3, 1)]);
+        assert_eq!(spans_and_deltas(&buckets), (vec![(-2, 2), (2, 2)], vec![2, 1, -2, 0]));
+        assert_eq!(spans_and_deltas(&BTreeMap::new()), (vec![], vec![]));
+    }
+
+    #[test]
+    fn zigzag_encoding() {
+        assert_eq!(zigzag32(0), 0);
+        assert_eq!(zigzag32(-1), 1);
+        assert_eq!(zigzag32(1), 2);
+        assert_eq!(zigzag64(-2), 3);
+    }
+}
@@ -0,0 +1,63 @@
+use serde_json::Value;
+
+/// Parses a JSON path like `$.data.orders[*].count` into its segments, `*` matches
+/// every field or array element and numbers index arrays. `$` alone is the root.
+pub fn parse(path: &str) -> Result<Vec<String>, String> {
+    let path = path.trim();
+    let normalized = path.replace('[', ".").replace(']', "");
+    let relative = match normalized.as_str() {
+        "$" => return Ok(vec![]),
+        normalized => normalized.strip_prefix("$.").unwrap_or(normalized),
+    };
+    let segments: Vec<String> = relative.split('.').map(String::from).collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(format!("invalid JSON path `{}`", path));
+    }
+    Ok(segments)
+}
+
+/// All values the path matches.
+pub fn select<'a>(value: &'a Value, path: &[String]) -> Vec<&'a Value> {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return vec![value],
+    };
+    let children: Vec<&Value> = match value {
+        Value::Object(map) if segment == "*" => map.values().collect(),
+        Value::Object(map) => map.get(segment).into_iter().collect(),
+        Value::Array(values) if segment == "*" => values.iter().collect(),
+        Value::Array(values) => segment.parse::<usize>().ok().and_then(|i| values.get(i)).into_iter().collect(),
+        _ => vec![],
+    };
+    children.into_iter().flat_map(|child| select(child, rest)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(parse("$").unwrap(), Vec::<String>::new());
+        assert_eq!(parse(" $.data.orders[*].count ").unwrap(), ["data", "orders", "*", "count"]);
+        assert_eq!(parse("data.orders[0]").unwrap(), ["data", "orders", "0"]);
+        assert_eq!(parse("$[0]").unwrap(), ["0"]);
+        assert_eq!(parse("$[*].x").unwrap(), ["*", "x"]);
+        assert!(parse("$.data..count").is_err());
+        assert!(parse("$.").is_err());
+    }
+
+    #[test]
+    fn selects_matching_values() {
+        let value = json!({"data": {"orders": [{"count": 1}, {"count": 2}, {"total": 3}]}});
+        let select_path = |path| select(&value, &parse(path).unwrap()).into_iter().cloned().collect::<Vec<_>>();
+        assert_eq!(select_path("$.data.orders[*].count"), [json!(1), json!(2)]);
+        assert_eq!(select_path("$.data.orders[1]"), [json!({"count": 2})]);
+        assert_eq!(select_path("$[*].orders[0].count"), [json!(1)]);
+        assert_eq!(select_path("$.data.*[2].total"), [json!(3)]);
+        assert!(select_path("$.data.orders[3]").is_empty());
+        assert!(select_path("$.data.orders.count").is_empty());
+        assert_eq!(select_path("$"), std::slice::from_ref(&value));
+    }
+}
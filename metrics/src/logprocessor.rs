@@ -1,13 +1,21 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use lazy_static::lazy_static;
 use log::warn;
 
+use regex::Regex;
 use serde::Deserialize;
 use serde_json::{from_str, from_value};
 use crate::Telemetry;
+use crate::outputs::LogOutputs;
 
 use opentelemetry::{
-    sdk::trace,
-    trace::{TraceContextExt, Tracer},KeyValue
+    propagation::{Extractor, TextMapPropagator},
+    sdk::{propagation::TraceContextPropagator, trace},
+    trace::{Span, SpanContext, SpanKind, Status, Tracer},KeyValue
 };
+use opentelemetry_semantic_conventions::trace as semconv;
 
 #[derive(Deserialize)]
 pub struct BaseLog {
@@ -21,6 +29,16 @@ pub struct BaseLog {
     pub detail: serde_json::Value,
 }
 
+impl BaseLog {
+    /// The time the line was logged at.
+    pub fn time(&self) -> Option<SystemTime> {
+        chrono::DateTime::parse_from_str(&self.timestamp, "%Y-%m-%dT%H:%M:%S%.f%z")
+            .or_else(|_| chrono::DateTime::parse_from_rfc3339(&self.timestamp))
+            .ok()
+            .map(SystemTime::from)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct HttpLogDetailHttpInfo {
     #[serde(rename = "status")]
@@ -31,14 +49,45 @@ pub struct HttpLogDetailHttpInfo {
     pub url: String,
     #[serde(rename = "method")]
     pub method: String,
-    #[serde(rename = "ip")]
+    // Missing if redacted
+    #[serde(rename = "ip", default)]
     pub ip: String,
+    #[serde(rename = "headers", default)]
+    pub headers: Option<RequestHeaders>,
+}
+
+/// The logged request headers, either as an object or as a list of name and value pairs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RequestHeaders {
+    Map(HashMap<String, String>),
+    List(Vec<RequestHeader>),
+}
+
+#[derive(Deserialize)]
+pub struct RequestHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl Extractor for RequestHeaders {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self {
+            RequestHeaders::Map(map) => map.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value.as_str()),
+            RequestHeaders::List(list) => list.iter().find(|h| h.name.eq_ignore_ascii_case(key)).map(|h| h.value.as_str()),
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            RequestHeaders::Map(map) => map.keys().map(|name| name.as_str()).collect(),
+            RequestHeaders::List(list) => list.iter().map(|h| h.name.as_str()).collect(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct HttpLogDetailOperationError {
-    #[serde(rename = "path")]
-    pub path: String,
     #[serde(rename = "error")]
     pub error: String,
     #[serde(rename = "code")]
@@ -57,16 +106,14 @@ pub struct HttpLogDetailOperationQuery {
 pub struct HttpLogDetailOperation {
     #[serde(rename = "query_execution_time")]
     pub query_execution_time: Option<f64>,
-    #[serde(rename = "request_id")]
-    pub request_id: String,
-    #[serde(rename = "parameterized_query_hash")]
-    pub parameterized_query_hash: Option<String>,
     #[serde(rename = "response_size")]
     pub response_size: i32,
     #[serde(rename = "error")]
     pub error: Option<HttpLogDetailOperationError>,
     #[serde(rename = "query")]
     pub query: Option<HttpLogDetailOperationQuery>,
+    #[serde(rename = "request_headers", default)]
+    pub request_headers: Option<RequestHeaders>,
 }
 
 #[derive(Deserialize)]
@@ -78,10 +125,98 @@ pub struct HttpLogDetails {
     pub http_info: HttpLogDetailHttpInfo,
 }
 
-async fn handle_http_log(log: &BaseLog, metric_obj: &Telemetry) {
+lazy_static! {
+    static ref OPERATION_DECLARATION: Regex = Regex::new(r"\b(query|mutation|subscription)\b\s*([_A-Za-z][_0-9A-Za-z]*)?").unwrap();
+}
+
+/// The type of the operation, as declared in the query document.
+fn operation_type(query: &str, operation_name: Option<&str>) -> Option<&'static str> {
+    let declaration = match operation_name {
+        Some(name) => OPERATION_DECLARATION.captures_iter(query)
+            .find(|declaration| declaration.get(2).map(|m| m.as_str()) == Some(name))?,
+        // Shorthand queries `{ ... }` have no declaration at all
+        None if query.trim_start().starts_with('{') => return Some("query"),
+        None => OPERATION_DECLARATION.captures(query).filter(|declaration| query[..declaration.get(0).unwrap().start()].trim().is_empty())?,
+    };
+    match declaration.get(1)?.as_str() {
+        "query" => Some("query"),
+        "mutation" => Some("mutation"),
+        _ => Some("subscription"),
+    }
+}
+
+/// A server span covering the execution of the request, which ended when the line was logged.
+/// With a logged `traceparent` header the span continues the trace of the client.
+fn request_span(log: &BaseLog, http: &HttpLogDetails, tracer: &trace::Tracer) -> (trace::Span, SystemTime) {
+    let end = log.time().unwrap_or_else(SystemTime::now);
+    let duration = http.operation.query_execution_time
+        .and_then(|t| Duration::try_from_secs_f64(t).ok())
+        .unwrap_or_default();
+
+    let mut attributes = vec![
+        semconv::HTTP_METHOD.string(http.http_info.method.clone()),
+        semconv::HTTP_STATUS_CODE.i64(http.http_info.status as i64),
+        semconv::HTTP_TARGET.string(http.http_info.url.clone()),
+        semconv::HTTP_FLAVOR.string(http.http_info.http_version.trim_start_matches("HTTP/").to_string()),
+        semconv::HTTP_RESPONSE_CONTENT_LENGTH.i64(http.operation.response_size as i64),
+        KeyValue::new("hasura.request_id", http.request_id.clone()),
+    ];
+
+    if !http.http_info.ip.is_empty() {
+        attributes.push(semconv::HTTP_CLIENT_IP.string(http.http_info.ip.clone()));
+    }
+
+    let operation_name = http.operation.query.as_ref().and_then(|q| q.operation_name.as_deref());
+    let operation_type = http.operation.query.as_ref()
+        .and_then(|q| q.query.as_deref())
+        .and_then(|query| operation_type(query, operation_name));
+    if let Some(name) = operation_name {
+        attributes.push(semconv::GRAPHQL_OPERATION_NAME.string(name.to_string()));
+    }
+    if let Some(operation_type) = operation_type {
+        attributes.push(semconv::GRAPHQL_OPERATION_TYPE.string(operation_type));
+    }
+    if let Some(error) = &http.operation.error {
+        attributes.push(KeyValue::new("hasura.error.code", error.code.clone()));
+    }
+    if let Some(time) = http.operation.query_execution_time {
+        attributes.push(KeyValue::new("hasura.query_execution_time", time));
+    }
+
+    let name = match (operation_type, operation_name) {
+        (Some(operation_type), Some(name)) => format!("{} {}", operation_type, name),
+        (Some(operation_type), None) => operation_type.to_string(),
+        _ => format!("{} {}", http.http_info.method, http.http_info.url),
+    };
+
+    let parent = http.http_info.headers.as_ref()
+        .or(http.operation.request_headers.as_ref())
+        .map(|headers| TraceContextPropagator::new().extract(headers))
+        .unwrap_or_default();
+
+    let mut span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_start_time(end.checked_sub(duration).unwrap_or(end))
+        .with_attributes(attributes)
+        .start_with_context(tracer, &parent);
+
+    if let Some(error) = &http.operation.error {
+        span.set_status(Status::error(error.error.clone()));
+    } else if http.http_info.status >= 500 {
+        span.set_status(Status::error(format!("HTTP status {}", http.http_info.status)));
+    }
+
+    (span, end)
+}
+
+async fn handle_http_log(log: &BaseLog, metric_obj: &Telemetry, outputs: &LogOutputs, tracer: &trace::Tracer) -> Option<SpanContext> {
     let detail_result = from_value::<HttpLogDetails>(log.detail.clone());
     match detail_result {
         Ok(http) => {
+            let (mut span, end) = request_span(log, &http, tracer);
+            outputs.http_log(log, &http, metric_obj).await;
+
             metric_obj.REQUEST_COUNTER
                 .with_label_values(&[
                     http.http_info.url.as_str(),
@@ -99,15 +234,26 @@ async fn handle_http_log(log: &BaseLog, metric_obj: &Telemetry) {
 
                 if let Some(exec_time) = http.operation.query_execution_time {
                     metric_obj.QUERY_EXECUTION_TIMES
-                        .with_label_values(&[operation.as_str(), error.as_str()])
-                        .observe(exec_time);
+                        .observe(&[operation.as_str(), error.as_str()], exec_time);
+
+                    // Link the observation to the request span, so slow buckets lead to the trace
+                    let mut exemplar = vec![("request_id".to_string(), http.request_id.clone())];
+                    let span_context = span.span_context();
+                    if span_context.is_sampled() {
+                        exemplar.push(("trace_id".to_string(), span_context.trace_id().to_string()));
+                    }
+                    metric_obj.QUERY_EXECUTION_EXEMPLARS.observe(&[operation.as_str(), error.as_str()], exec_time, exemplar);
                 }
             }
+
+            span.end_with_timestamp(end);
+            Some(span.span_context().clone())
         }
         Err(e) => {
             eprintln!("Invalid HTTP log detail: {}", e);
+            None
         }
-    };
+    }
 }
 
 #[derive(Deserialize)]
@@ -131,27 +277,21 @@ pub struct WebSocketDetailEventDetailOperationType {
 pub struct WebSocketDetailEventDetail {
     #[serde(rename = "operation_name")]
     pub operation_name: Option<String>,
-    #[serde(rename = "request_id")]
-    pub request_id: Option<String>,
     #[serde(rename = "operation_type")]
     pub operation_type: WebSocketDetailEventDetailOperationType,
 }
 
-#[derive(Deserialize)]
-pub struct WebSocketDetailConnInfo {}
-
 #[derive(Deserialize)]
 pub struct WebSocketDetail {
     #[serde(rename = "event")]
     pub event: WebSocketDetailEvent,
-    #[serde(rename = "connection_info")]
-    pub connection_info: WebSocketDetailConnInfo,
 }
 
-async fn handle_websocket_log(log: &BaseLog, metric_obj: &Telemetry) {
+async fn handle_websocket_log(log: &BaseLog, metric_obj: &Telemetry, outputs: &LogOutputs) {
     let detail_result = from_value::<WebSocketDetail>(log.detail.clone());
     match detail_result {
         Ok(http) => {
+            outputs.websocket_log(&http, metric_obj).await;
             match &http.event.event_type as &str {
                 "accepted" => metric_obj.ACTIVE_WEBSOCKET.inc(),
                 "closed" => metric_obj.ACTIVE_WEBSOCKET.dec(),
@@ -188,38 +328,54 @@ async fn handle_websocket_log(log: &BaseLog, metric_obj: &Telemetry) {
     };
 }
 
-pub async fn log_processor(logline: &String, metric_obj: &Telemetry, tracer: &trace::Tracer) {
+pub async fn log_processor(logline: &str, metric_obj: &Telemetry, outputs: &LogOutputs, tracer: &trace::Tracer) {
     //println!("{}", logline);
     metric_obj.LOG_LINES_COUNTER_TOTAL.inc();
+    let logline = outputs.redact(logline);
+    let logline = logline.as_ref();
     let log_result = from_str::<BaseLog>(logline);
     match log_result {
         Ok(log) => {
             metric_obj.LOG_LINES_COUNTER
                 .with_label_values(&[log.logtype.as_str()])
                 .inc();
-            match &log.logtype as &str {
+
+            let span_context = match &log.logtype as &str {
                 "http-log" => {
-                    handle_http_log(&log,metric_obj).await;
+                    handle_http_log(&log,metric_obj,outputs,tracer).await
                 }
                 "websocket-log" => {
-                    handle_websocket_log(&log,metric_obj).await;
+                    handle_websocket_log(&log,metric_obj,outputs).await;
+                    None
                 }
-                _ => {}
+                _ => None
             };
-            
-            //Send log to opentel:
-            tracer.in_span("hasura-log", |cx| {
-                let span = cx.span();
-                span.add_event(                            
-                    logline.clone(),
-                    vec![],
-                );
-                span.set_attribute(KeyValue::new("hasura-timestamp", log.timestamp));
-                span.set_attribute(KeyValue::new("hasura-logtype", log.logtype));
-            });
+
+            //Send log to opentel, correlated with the request span:
+            outputs.log(logline, &log, span_context.as_ref(), metric_obj).await;
         }
         Err(e) => {
             warn!("Failed to parse log line: {}", e);
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::operation_type;
+
+    #[test]
+    fn operation_type_of_named_operation() {
+        let query = "query Users { users { id } } mutation AddUser { insert_users_one(object: {}) { id } }";
+        assert_eq!(operation_type(query, Some("AddUser")), Some("mutation"));
+        assert_eq!(operation_type(query, Some("Users")), Some("query"));
+        assert_eq!(operation_type(query, Some("User")), None);
+    }
+
+    #[test]
+    fn operation_type_of_anonymous_operation() {
+        assert_eq!(operation_type("{ users { id } }", None), Some("query"));
+        assert_eq!(operation_type("  subscription { users { id } }", None), Some("subscription"));
+        assert_eq!(operation_type("fragment F on users { id } query { users { ...F } }", None), None);
+    }
+}
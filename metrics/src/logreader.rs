@@ -11,10 +11,13 @@ use std::time::Duration;
 
 
 use crate::{logprocessor, Telemetry};
+use crate::outputs::LogOutputs;
 use opentelemetry::sdk::trace;
 
 
-pub async fn read_file(tracer: &trace::Tracer, log_file: &str, metric_obj: &Telemetry, sleep_time: u64, mut termination_rx: watch::Receiver<()>) -> Result<()> {
+/// Follows the log file until the adapter terminates, or with `exit_on_eof`
+/// until the end of the file has been reached.
+pub async fn read_file(tracer: &trace::Tracer, log_file: &str, metric_obj: &Telemetry, outputs: &LogOutputs, sleep_time: u64, exit_on_eof: bool, mut termination_rx: watch::Receiver<()>) -> Result<()> {
     loop {
         tokio::select! {
             biased;
@@ -29,7 +32,7 @@ pub async fn read_file(tracer: &trace::Tracer, log_file: &str, metric_obj: &Tele
                 match result {
                     Ok(file) => {
                         info!("Hasura log file {} open, will follow the log", log_file);
-                        match process_file(tracer, metric_obj, &file, sleep_time, termination_rx.clone()).await {
+                        match process_file(tracer, metric_obj, outputs, &file, sleep_time, exit_on_eof, termination_rx.clone()).await {
                             Ok(true) => (),
                             Ok(false) => return Ok(()),
                             Err(e) => {
@@ -53,7 +56,7 @@ pub async fn read_file(tracer: &trace::Tracer, log_file: &str, metric_obj: &Tele
     }
 }
 
-async fn process_file(tracer: &trace::Tracer, metric_obj: &Telemetry, file: &File, sleep_time: u64, mut termination_rx: watch::Receiver<()>) -> Result<bool> {
+async fn process_file(tracer: &trace::Tracer, metric_obj: &Telemetry, outputs: &LogOutputs, file: &File, sleep_time: u64, exit_on_eof: bool, mut termination_rx: watch::Receiver<()>) -> Result<bool> {
     let reader = BufReader::new(file.try_clone().await?);
     let mut lines = reader.lines();
 
@@ -63,13 +66,16 @@ async fn process_file(tracer: &trace::Tracer, metric_obj: &Telemetry, file: &Fil
             _ = termination_rx.changed() => return Ok(false),
 
             next_line = lines.next_line() => {
-                if was_file_removed(&file).await? {
+                if was_file_removed(file).await? {
                     return Ok(true)
                 }
 
                 if let Some(line) = next_line? {
                     debug!("Reading line from logfile");
-                    logprocessor::log_processor(&line, metric_obj,tracer).await;
+                    logprocessor::log_processor(&line, metric_obj, outputs, tracer).await;
+                } else if exit_on_eof {
+                    info!("Reached the end of the hasura log file");
+                    return Ok(false)
                 } else {
                     time::sleep(Duration::from_millis(sleep_time)).await;
                 }
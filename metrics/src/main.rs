@@ -1,6 +1,7 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use actix_web::{App, get, HttpServer, Responder};
+use actix_web::{App, get, http::header, middleware, web, HttpRequest, HttpResponse, HttpServer};
 
 use clap::Parser;
 use clap::builder::TypedValueParser;
@@ -8,42 +9,91 @@ use clap::builder::TypedValueParser;
 use regex::Regex;
 use log::{info, warn, debug, error};
 
-use prometheus::{Encoder, TextEncoder};
+use prometheus::proto::MetricFamily;
 use tokio::sync::watch;
-use crate::telemetry::Telemetry;
-use opentelemetry::{
-    global, runtime,
-    sdk::{trace, Resource},
-    trace::TraceError,KeyValue,
-};
-use opentelemetry_otlp::WithExportConfig;
+use crate::exposition::{Format, SeriesCreated};
+use crate::histogram::{HistogramConfig, HistogramType};
+use crate::outputs::statsd::StatsdFlavor;
+use crate::redaction::RedactRule;
+use crate::telemetry::{MetricNaming, Telemetry};
+use crate::opentel::{OpentelProtocol, OpentelSampler};
+use opentelemetry::global;
 
 mod logreader;
 mod logprocessor;
 mod collectors;
+mod outputs;
+mod opentel;
+mod redaction;
 
+mod exposition;
+mod histogram;
+mod jsonpath;
 mod telemetry;
 
 
-#[get("/metrics")]
-async fn metrics() -> impl Responder {
-    let mut buffer = Vec::new();
-    let encoder = TextEncoder::new();
+/// The creation times of the series of each route, which expose different registries.
+struct RouteSeries {
+    metrics: SeriesCreated,
+    self_metrics: SeriesCreated,
+}
 
+#[get("/metrics")]
+async fn metrics(req: HttpRequest, series: web::Data<RouteSeries>, metric_obj: web::Data<Telemetry>) -> HttpResponse {
     // Gather the metrics.
-    let metric_families = prometheus::gather();
-    // Encode them to send.
-    encoder.encode(&metric_families, &mut buffer).unwrap();
+    let metric_families = metric_obj.registry.gather();
+    exposition_response(&req, &series.metrics, &metric_obj, metric_families)
+}
+
+async fn self_metrics(req: HttpRequest, series: web::Data<RouteSeries>, metric_obj: web::Data<Telemetry>) -> HttpResponse {
+    let metric_families = metric_obj.self_registry.gather();
+    exposition_response(&req, &series.self_metrics, &metric_obj, metric_families)
+}
 
-    String::from_utf8(buffer.clone()).unwrap()
+fn exposition_response(req: &HttpRequest, created: &SeriesCreated, metric_obj: &Telemetry, mut metric_families: Vec<MetricFamily>) -> HttpResponse {
+    let accept = req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    let format = Format::negotiate(accept);
+
+    // Encode them to send.
+    match exposition::encode(format, &mut metric_families, created, &metric_obj.exemplars()) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(body),
+        Err(e) => {
+            error!("Failed to encode metrics as {:?}: {}", format, e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
 }
 
-async fn webserver(cfg: &Configuration) -> std::io::Result<()> {
+async fn webserver(cfg: &Configuration, metric_obj: &Telemetry, mut termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
     warn!("Starting metric server @ {}", cfg.listen_addr);
-    HttpServer::new(|| App::new().service(metrics))
+    let series = web::Data::new(RouteSeries { metrics: SeriesCreated::new(), self_metrics: SeriesCreated::new() });
+    let metric_obj = web::Data::new(metric_obj.clone());
+    let self_metrics_path = cfg.self_metrics_path.clone();
+    let server = HttpServer::new(move || {
+        let app = App::new()
+            .wrap(middleware::Compress::default())
+            .app_data(series.clone())
+            .app_data(metric_obj.clone())
+            .service(metrics);
+        match &self_metrics_path {
+            Some(path) => app.route(path, web::get().to(self_metrics)),
+            None => app,
+        }
+    })
         .bind(&cfg.listen_addr)?
-        .run()
-        .await
+        .disable_signals()
+        .run();
+
+    // Stop together with the other tasks, so the outputs can push a final time
+    let handle = server.handle();
+    tokio::spawn(async move {
+        let _ = termination_rx.changed().await;
+        handle.stop(true).await;
+    });
+
+    server.await
 }
 
 #[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -52,10 +102,59 @@ pub(crate) enum Collectors {
     EventTriggers,
     ScheduledEvents,
     MetadataInconsistency,
+    AsyncActions,
+    RemoteSchemas,
+    MetadataInventory,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum OpentelSignal {
+    Traces,
+    Logs,
+}
+
+/// An option value which is printed as `***`, so the configuration can be logged.
+#[derive(Clone)]
+pub struct Secret<T>(T);
+
+impl<T> std::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl<T> std::ops::Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: std::str::FromStr> std::str::FromStr for Secret<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Secret)
+    }
+}
+
+/// Parses the value of a secret option with the parser of the value.
+impl<P: TypedValueParser> TypedValueParser for Secret<P> {
+    type Value = Secret<P::Value>;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        self.0.parse_ref(cmd, arg, value).map(Secret)
+    }
 }
 
 fn key_value_parser(input: &str) -> Result<(String, String), String> {
-    let pair: Vec<&str> = Regex::new(r"=").unwrap().split(&input).collect();
+    let pair: Vec<&str> = Regex::new(r"=").unwrap().split(input).collect();
     match pair.len() {
         2 => Ok((String::from(pair[0]),String::from(pair[1]))),
         _ => Err(format!("invalid KEY=value: no `=` found in `{}`",input)),
@@ -65,7 +164,7 @@ fn key_value_parser(input: &str) -> Result<(String, String), String> {
 /// Implementation for [`ValueParser::string`]
 ///
 /// Useful for composing new [`TypedValueParser`]s
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 #[non_exhaustive]
 pub struct MapValueParser {}
 impl MapValueParser {
@@ -93,71 +192,243 @@ impl TypedValueParser for MapValueParser {
     }
 }
 
+/// Parses `metric=bucket:bucket:...` entries separated by `,` into the buckets per
+/// metric name.
+#[derive(Copy, Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct BucketsValueParser {}
+impl BucketsValueParser {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl TypedValueParser for BucketsValueParser {
+    type Value = HashMap<String,Vec<f64>>;
+
+    fn parse_ref(
+        &self,
+        _cmd: &clap::Command,
+        _arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let mut map = HashMap::new();
+        for entry in value.to_str().unwrap_or_default().split(',').filter(|v| !v.is_empty()) {
+            let (metric, buckets) = key_value_parser(entry)
+                .map_err(|e| clap::Error::raw(clap::ErrorKind::InvalidValue, e))?;
+            let buckets = buckets
+                .split(':')
+                .map(|v| v.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>,_>>()
+                .map_err(|e| clap::Error::raw(clap::ErrorKind::InvalidValue, format!("invalid buckets for `{}`: {}", metric, e)))?;
+            map.insert(metric, buckets);
+        }
+        Ok(map)
+    }
+}
+
 #[derive(Parser,Debug)]
 #[clap(author, version, about)]
 pub(crate) struct Configuration {
     #[clap(name ="listen", long = "listen", env = "LISTEN_ADDR", default_value = "0.0.0.0:9090")]
     listen_addr: String,
 
+    #[clap(name ="self-metrics-path", long = "self-metrics-path", env = "SELF_METRICS_PATH")]
+    self_metrics_path: Option<String>,
+
     #[clap(name ="hasura-endpoint", long = "hasura-endpoint", env = "HASURA_GRAPHQL_ENDPOINT", default_value = "http://localhost:8080")]
     hasura_addr: String,
 
-    #[clap(name ="opentel-endpoint", long = "opentel-endpoint", env = "OPENTEL_ENDPOINT", default_value = "http://localhost:4317")]
-    opentel_addr: String,
+    #[clap(name ="opentel-endpoint", long = "opentel-endpoint", env = "OPENTEL_ENDPOINT")]
+    opentel_addr: Option<String>,
+
+    #[clap(name ="opentel-protocol", long = "opentel-protocol", env = "OPENTEL_PROTOCOL", value_parser, default_value = "grpc")]
+    opentel_protocol: OpentelProtocol,
+
+    #[clap(name ="opentel-headers", long = "opentel-headers", env = "OPENTEL_HEADERS", value_parser = Secret(MapValueParser::new()))]
+    opentel_headers: Option<Secret<HashMap<String,String>>>,
+
+    #[clap(name ="opentel-ca-cert", long = "opentel-ca-cert", env = "OPENTEL_CA_CERT")]
+    opentel_ca_cert: Option<String>,
+
+    #[clap(name ="opentel-client-cert", long = "opentel-client-cert", env = "OPENTEL_CLIENT_CERT", requires = "opentel-client-key")]
+    opentel_client_cert: Option<String>,
+
+    #[clap(name ="opentel-client-key", long = "opentel-client-key", env = "OPENTEL_CLIENT_KEY", requires = "opentel-client-cert")]
+    opentel_client_key: Option<String>,
+
+    #[clap(name ="opentel-sampler", long = "opentel-sampler", env = "OPENTEL_SAMPLER", value_parser, default_value = "always-on")]
+    opentel_sampler: OpentelSampler,
+
+    #[clap(name ="opentel-sampler-ratio", long = "opentel-sampler-ratio", env = "OPENTEL_SAMPLER_RATIO", default_value = "1.0")]
+    opentel_sampler_ratio: f64,
+
+    #[clap(name ="opentel-slow-threshold", long = "opentel-slow-threshold", env = "OPENTEL_SLOW_THRESHOLD", default_value = "1000")]
+    opentel_slow_threshold: u64,
+
+    #[clap(name ="otlp-metrics-endpoint", long = "otlp-metrics-endpoint", env = "OTLP_METRICS_ENDPOINT")]
+    otlp_metrics_endpoint: Option<String>,
+
+    #[clap(name ="otlp-metrics-interval", long = "otlp-metrics-interval", env = "OTLP_METRICS_INTERVAL", default_value = "60000", value_parser = clap::value_parser!(u64).range(1..))]
+    otlp_metrics_interval: u64,
+
+    #[clap(name ="remote-write-url", long = "remote-write-url", env = "REMOTE_WRITE_URL")]
+    remote_write_url: Option<String>,
+
+    #[clap(name ="remote-write-interval", long = "remote-write-interval", env = "REMOTE_WRITE_INTERVAL", default_value = "15000", value_parser = clap::value_parser!(u64).range(1..))]
+    remote_write_interval: u64,
+
+    #[clap(name ="remote-write-username", long = "remote-write-username", env = "REMOTE_WRITE_USERNAME")]
+    remote_write_username: Option<String>,
+
+    #[clap(name ="remote-write-password", long = "remote-write-password", env = "REMOTE_WRITE_PASSWORD")]
+    remote_write_password: Option<Secret<String>>,
+
+    #[clap(name ="remote-write-bearer-token", long = "remote-write-bearer-token", env = "REMOTE_WRITE_BEARER_TOKEN", conflicts_with = "remote-write-username")]
+    remote_write_bearer_token: Option<Secret<String>>,
+
+    #[clap(name ="remote-write-timeout", long = "remote-write-timeout", env = "REMOTE_WRITE_TIMEOUT", default_value = "30000")]
+    remote_write_timeout: u64,
+
+    #[clap(name ="remote-write-max-retries", long = "remote-write-max-retries", env = "REMOTE_WRITE_MAX_RETRIES", default_value = "3")]
+    remote_write_max_retries: u32,
+
+    #[clap(name ="remote-write-min-backoff", long = "remote-write-min-backoff", env = "REMOTE_WRITE_MIN_BACKOFF", default_value = "30")]
+    remote_write_min_backoff: u64,
+
+    #[clap(name ="remote-write-max-backoff", long = "remote-write-max-backoff", env = "REMOTE_WRITE_MAX_BACKOFF", default_value = "5000")]
+    remote_write_max_backoff: u64,
+
+    #[clap(name ="statsd-address", long = "statsd-address", env = "STATSD_ADDRESS")]
+    statsd_address: Option<String>,
+
+    #[clap(name ="statsd-prefix", long = "statsd-prefix", env = "STATSD_PREFIX", default_value = "hasura")]
+    statsd_prefix: String,
+
+    #[clap(name ="statsd-flavor", long = "statsd-flavor", env = "STATSD_FLAVOR", value_parser, default_value = "dogstatsd")]
+    statsd_flavor: StatsdFlavor,
+
+    #[clap(name ="influxdb-url", long = "influxdb-url", env = "INFLUXDB_URL")]
+    influxdb_url: Option<String>,
+
+    #[clap(name ="influxdb-token", long = "influxdb-token", env = "INFLUXDB_TOKEN")]
+    influxdb_token: Option<Secret<String>>,
+
+    #[clap(name ="influxdb-file", long = "influxdb-file", env = "INFLUXDB_FILE", conflicts_with = "influxdb-url")]
+    influxdb_file: Option<String>,
+
+    #[clap(name ="influxdb-measurement", long = "influxdb-measurement", env = "INFLUXDB_MEASUREMENT", default_value = "hasura_requests")]
+    influxdb_measurement: String,
+
+    #[clap(name ="influxdb-batch-size", long = "influxdb-batch-size", env = "INFLUXDB_BATCH_SIZE", default_value = "1000")]
+    influxdb_batch_size: usize,
+
+    #[clap(name ="influxdb-flush-interval", long = "influxdb-flush-interval", env = "INFLUXDB_FLUSH_INTERVAL", default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    influxdb_flush_interval: u64,
+
+    #[clap(name ="pushgateway-url", long = "pushgateway-url", env = "PUSHGATEWAY_URL")]
+    pushgateway_url: Option<String>,
+
+    #[clap(name ="pushgateway-job", long = "pushgateway-job", env = "PUSHGATEWAY_JOB", default_value = "hasura-metrics-adapter")]
+    pushgateway_job: String,
+
+    #[clap(name ="pushgateway-interval", long = "pushgateway-interval", env = "PUSHGATEWAY_INTERVAL", value_parser = clap::value_parser!(u64).range(1..))]
+    pushgateway_interval: Option<u64>,
+
+    #[clap(name ="opentel-signals", long = "opentel-signals", env = "OPENTEL_SIGNALS", value_parser, value_delimiter(';'), default_value = "traces;logs")]
+    opentel_signals: Vec<OpentelSignal>,
+
+    #[clap(name ="redact", long = "redact", env = "REDACT", value_parser, value_delimiter(';'))]
+    redact: Vec<RedactRule>,
+
+    #[clap(name ="redact-salt", long = "redact-salt", env = "REDACT_SALT")]
+    redact_salt: Option<Secret<String>>,
 
     #[clap(name ="hasura-admin-secret", long = "hasura-admin-secret", env = "HASURA_GRAPHQL_ADMIN_SECRET")]
-    hasura_admin: Option<String>,
+    hasura_admin: Option<Secret<String>>,
 
     #[clap(name ="logfile", long = "logfile", env = "LOG_FILE")]
     log_file: String,
 
+    #[clap(name ="exit-on-eof", long = "exit-on-eof", env = "EXIT_ON_EOF")]
+    exit_on_eof: bool,
+
     #[clap(name ="sleep", long = "sleep", env = "SLEEP_TIME", default_value = "1000")]
     sleep_time: u64,
 
     #[clap(name ="collect-interval", long = "collect-interval", env = "COLLECT_INTERVAL", default_value = "15000")]
     collect_interval: u64,
 
+    #[clap(name ="custom-metrics-file", long = "custom-metrics-file", env = "CUSTOM_METRICS_FILE")]
+    custom_metrics_file: Option<String>,
+
+    #[clap(name ="metadata-source", long = "metadata-source", env = "METADATA_SOURCE")]
+    metadata_source: Option<String>,
+
+    #[clap(name ="incremental-counts", long = "incremental-counts", env = "INCREMENTAL_COUNTS")]
+    incremental_counts: bool,
+
+    #[clap(name ="invocation-window", long = "invocation-window", env = "INVOCATION_WINDOW", default_value = "300000")]
+    invocation_window: u64,
+
+    #[clap(name ="remote-schema-ping-query", long = "remote-schema-ping-query", env = "REMOTE_SCHEMA_PING_QUERY", default_value = "{ __typename }")]
+    remote_schema_ping_query: String,
+
     #[clap(name ="exclude-collectors", long = "exclude-collectors", env = "EXCLUDE_COLLECTORS", value_parser, value_delimiter(';'))]
     disabled_collectors: Vec<Collectors>,
 
     #[clap(name ="common-labels", short = 'l', long = "common-labels", env = "COMMON_LABELS", value_parser = MapValueParser::new())]
     common_labels: Option<HashMap<String,String>>,
 
+    #[clap(name ="metric-prefix", long = "metric-prefix", env = "METRIC_PREFIX", default_value = "hasura")]
+    metric_prefix: String,
+
+    #[clap(name ="rename-metrics", long = "rename-metrics", env = "RENAME_METRICS", value_parser = MapValueParser::new())]
+    rename_metrics: Option<HashMap<String,String>>,
+
+    #[clap(name ="drop-metrics", long = "drop-metrics", env = "DROP_METRICS", value_parser, value_delimiter(';'))]
+    drop_metrics: Vec<String>,
+
     #[clap(name ="histogram-buckets", long = "histogram-buckets", env = "HISTOGRAM_BUCKETS", value_parser, value_delimiter(';'))]
     histogram_buckets: Vec<f64>,
 
+    #[clap(name ="metric-histogram-buckets", long = "metric-histogram-buckets", env = "METRIC_HISTOGRAM_BUCKETS", value_parser = BucketsValueParser::new())]
+    metric_histogram_buckets: Option<HashMap<String,Vec<f64>>>,
+
+    #[clap(name ="histogram-type", long = "histogram-type", env = "HISTOGRAM_TYPE", value_parser, default_value = "classic")]
+    histogram_type: HistogramType,
+
+    #[clap(name ="native-histogram-schema", long = "native-histogram-schema", env = "NATIVE_HISTOGRAM_SCHEMA", default_value = "3", value_parser = clap::value_parser!(i32).range(-4..=8))]
+    native_histogram_schema: i32,
+
+    #[clap(name ="native-histogram-zero-threshold", long = "native-histogram-zero-threshold", env = "NATIVE_HISTOGRAM_ZERO_THRESHOLD", default_value = "2.938735877055719e-39")]
+    native_histogram_zero_threshold: f64,
+
+    #[clap(name ="native-histogram-max-buckets", long = "native-histogram-max-buckets", env = "NATIVE_HISTOGRAM_MAX_BUCKETS", default_value = "160")]
+    native_histogram_max_buckets: usize,
+
     #[clap(name ="concurrency-limit", long = "concurrency-limit", env = "CONCURRENCY_LIMIT", default_value = "0")]
     concurrency_limit: usize,
 }
 
-async fn signal_handler_ctrl_c(tx: watch::Sender<()>) -> std::io::Result<()> {
-    tokio::signal::ctrl_c().await?;
-    warn!("Terminating due to ctrl+c");
+async fn signal_handler_terminate(tx: Arc<watch::Sender<()>>) -> std::io::Result<()> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            warn!("Terminating due to ctrl+c");
+        }
+        _ = sigterm.recv() => warn!("Terminating due to SIGTERM"),
+    }
     let _ = tx.send(());
     Ok(())
 }
 
-fn signal_handler() -> watch::Receiver<()> {
+fn signal_handler() -> (Arc<watch::Sender<()>>, watch::Receiver<()>) {
     let (terminate_tx, terminate_rx) = watch::channel(());
-    tokio::spawn(signal_handler_ctrl_c(terminate_tx));
-    terminate_rx
-}
-
-fn init_tracer(opentel_addr: &str) -> Result<trace::Tracer, TraceError> {
-    opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(opentel_addr),
-        )
-        .with_trace_config(
-            trace::config().with_resource(Resource::new(vec![KeyValue::new(
-                opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-                "hasura-metrics-adapter",
-            )])),
-        )
-        .install_batch(runtime::Tokio)
+    let terminate_tx = Arc::new(terminate_tx);
+    tokio::spawn(signal_handler_terminate(terminate_tx.clone()));
+    (terminate_tx, terminate_rx)
 }
 
 #[tokio::main]
@@ -166,7 +437,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut config = Configuration::parse();
 
     // Initialize the opentel tracer
-    let tracer = init_tracer(&config.opentel_addr)?;
+    let tracer = opentel::init_tracer(&config)?;
 
     if config.hasura_admin.is_none() {
         let admin_collectors = [
@@ -174,6 +445,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Collectors::EventTriggers,
             Collectors::ScheduledEvents,
             Collectors::MetadataInconsistency,
+            Collectors::AsyncActions,
+            Collectors::RemoteSchemas,
+            Collectors::MetadataInventory,
         ];
 
         config.disabled_collectors.extend_from_slice(&admin_collectors);
@@ -188,14 +462,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     debug!("Configuration: {:?}", config);
 
-    let terminate_rx = signal_handler();
-
-    let metric_obj: Telemetry = Telemetry::new(config.common_labels.clone().unwrap_or_default(),config.histogram_buckets.clone());
+    let (terminate_tx, terminate_rx) = signal_handler();
+
+    let histograms = HistogramConfig {
+        histogram_type: config.histogram_type,
+        buckets: config.histogram_buckets.clone(),
+        metric_buckets: config.metric_histogram_buckets.clone().unwrap_or_default(),
+        native_schema: config.native_histogram_schema,
+        native_zero_threshold: config.native_histogram_zero_threshold,
+        native_max_buckets: config.native_histogram_max_buckets,
+    };
+    let naming = MetricNaming {
+        prefix: config.metric_prefix.clone(),
+        renames: config.rename_metrics.clone().unwrap_or_default(),
+        dropped: config.drop_metrics.clone(),
+    };
+    let metric_obj: Telemetry = Telemetry::new(config.common_labels.clone().unwrap_or_default(),naming,histograms,config.self_metrics_path.is_some())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid metric names: {}", e)))?;
+
+    let log_outputs = outputs::LogOutputs::new(&config).await?;
+    let custom_metrics = collectors::CustomMetrics::load(&config, &metric_obj)?;
 
     let res = tokio::try_join!(
-        webserver(&config),
-        logreader::read_file(&tracer, &config.log_file, &metric_obj, config.sleep_time, terminate_rx.clone()),
-        collectors::run_metadata_collector(&config, &metric_obj, terminate_rx.clone())
+        webserver(&config, &metric_obj, terminate_rx.clone()),
+        async {
+            logreader::read_file(&tracer, &config.log_file, &metric_obj, &log_outputs, config.sleep_time, config.exit_on_eof, terminate_rx.clone()).await?;
+            // Without more log lines to read there is nothing left to do for the other tasks
+            let _ = terminate_tx.send(());
+            Ok(())
+        },
+        collectors::run_metadata_collector(&config, &metric_obj, terminate_rx.clone()),
+        custom_metrics.run(&config, &metric_obj, terminate_rx.clone()),
+        outputs::run_metric_outputs(&config, &metric_obj, &log_outputs, terminate_rx.clone())
     );
 
     match res {
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::info;
+use opentelemetry::sdk::trace::{self, Sampler, ShouldSample};
+use opentelemetry::sdk::{runtime, Resource};
+use opentelemetry::trace::{Link, OrderMap, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceError, TraceId, TracerProvider as _};
+use opentelemetry::{Context, InstrumentationLibrary, Key, KeyValue, Value};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_proto::tonic::collector::logs::v1::{logs_service_client::LogsServiceClient, ExportLogsServiceRequest};
+use opentelemetry_proto::tonic::collector::metrics::v1::{metrics_service_client::MetricsServiceClient, ExportMetricsServiceRequest, ExportMetricsServiceResponse};
+use prost::Message;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+use crate::{Configuration, OpentelSignal};
+
+pub const SERVICE_NAME: &str = "hasura-metrics-adapter";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpentelProtocol {
+    Grpc,
+    HttpProtobuf,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpentelSampler {
+    AlwaysOn,
+    AlwaysOff,
+    /// A ratio of the traces
+    Ratio,
+    /// Like the parent span, a ratio of the traces without parent
+    ParentRatio,
+    /// Requests with a GraphQL error or a 5xx status
+    Errors,
+    /// Requests slower than the threshold
+    Slow,
+    ErrorsOrSlow,
+}
+
+/// The resource attributes of all exported signals, the common labels describe
+/// the adapter just as well as its metrics.
+pub fn resource_attributes(common_labels: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut attributes: Vec<(String, String)> = common_labels.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    attributes.sort();
+    attributes.insert(0, (opentelemetry_semantic_conventions::resource::SERVICE_NAME.to_string(), SERVICE_NAME.to_string()));
+    attributes
+}
+
+/// The tracer for the request spans. Without exporting traces, spans are not sampled.
+pub fn init_tracer(cfg: &Configuration) -> Result<trace::Tracer, TraceError> {
+    let endpoint = match &cfg.opentel_addr {
+        Some(endpoint) if cfg.opentel_signals.contains(&OpentelSignal::Traces) => endpoint,
+        _ => {
+            return Ok(trace::TracerProvider::builder()
+                .with_config(trace::config().with_sampler(Sampler::AlwaysOff))
+                .build()
+                .tracer(SERVICE_NAME))
+        }
+    };
+
+    info!("Exporting traces to {} via {:?}", endpoint, cfg.opentel_protocol);
+
+    let resource = Resource::new(
+        resource_attributes(&cfg.common_labels.clone().unwrap_or_default())
+            .into_iter()
+            .map(|(k, v)| KeyValue::new(k, v)),
+    );
+    let trace_config = trace::config()
+        .with_resource(resource)
+        .with_sampler(sampler(cfg));
+    let headers = cfg.opentel_headers.as_deref().cloned().unwrap_or_default();
+
+    let pipeline = opentelemetry_otlp::new_pipeline().tracing().with_trace_config(trace_config);
+    match cfg.opentel_protocol {
+        OpentelProtocol::Grpc => {
+            let mut exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint)
+                .with_metadata(metadata(&headers).map_err(|e| TraceError::Other(e.into()))?);
+            if let Some(tls_config) = tonic_tls_config(cfg, endpoint).map_err(|e| TraceError::Other(e.into()))? {
+                exporter = exporter.with_tls_config(tls_config);
+            }
+            pipeline.with_exporter(exporter).install_batch(runtime::Tokio)
+        }
+        OpentelProtocol::HttpProtobuf => {
+            let exporter = opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .with_headers(headers)
+                .with_http_client(http_client(cfg).map_err(|e| TraceError::Other(e.into()))?);
+            pipeline.with_exporter(exporter).install_batch(runtime::Tokio)
+        }
+    }
+}
+
+fn sampler(cfg: &Configuration) -> Sampler {
+    let ratio = cfg.opentel_sampler_ratio;
+    let threshold = Duration::from_millis(cfg.opentel_slow_threshold).as_secs_f64();
+    match cfg.opentel_sampler {
+        OpentelSampler::AlwaysOn => Sampler::AlwaysOn,
+        OpentelSampler::AlwaysOff => Sampler::AlwaysOff,
+        OpentelSampler::Ratio => Sampler::TraceIdRatioBased(ratio),
+        OpentelSampler::ParentRatio => Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio))),
+        OpentelSampler::Errors => Sampler::ParentBased(Box::new(RequestSampler { errors: true, slow_threshold: None })),
+        OpentelSampler::Slow => Sampler::ParentBased(Box::new(RequestSampler { errors: false, slow_threshold: Some(threshold) })),
+        OpentelSampler::ErrorsOrSlow => Sampler::ParentBased(Box::new(RequestSampler { errors: true, slow_threshold: Some(threshold) })),
+    }
+}
+
+/// Samples request spans by the outcome of the request, which is known when the
+/// span is started from the log line.
+#[derive(Clone, Debug)]
+struct RequestSampler {
+    errors: bool,
+    slow_threshold: Option<f64>,
+}
+
+impl ShouldSample for RequestSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        _trace_id: TraceId,
+        _name: &str,
+        _span_kind: &SpanKind,
+        attributes: &OrderMap<Key, Value>,
+        _links: &[Link],
+        _instrumentation_library: &InstrumentationLibrary,
+    ) -> SamplingResult {
+        let failed = attributes.contains_key(&Key::from_static_str("hasura.error.code"))
+            || matches!(attributes.get(&opentelemetry_semantic_conventions::trace::HTTP_STATUS_CODE), Some(Value::I64(status)) if *status >= 500);
+        let slow = match (self.slow_threshold, attributes.get(&Key::from_static_str("hasura.query_execution_time"))) {
+            (Some(threshold), Some(Value::F64(time))) => *time >= threshold,
+            _ => false,
+        };
+
+        SamplingResult {
+            decision: if (self.errors && failed) || slow { SamplingDecision::RecordAndSample } else { SamplingDecision::Drop },
+            attributes: vec![],
+            trace_state: parent_context
+                .map(|cx| cx.span().span_context().trace_state().clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn metadata(headers: &HashMap<String, String>) -> Result<MetadataMap, String> {
+    let mut metadata = MetadataMap::new();
+    for (name, value) in headers {
+        let key = MetadataKey::from_str(&name.to_lowercase()).map_err(|e| format!("Invalid header {}: {}", name, e))?;
+        let value = MetadataValue::try_from(value.as_str()).map_err(|e| format!("Invalid value of header {}: {}", name, e))?;
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))
+}
+
+/// TLS is used for `https` endpoints, with the system roots unless a CA is configured.
+fn tonic_tls_config(cfg: &Configuration, endpoint: &str) -> Result<Option<ClientTlsConfig>, String> {
+    if !endpoint.starts_with("https://") && cfg.opentel_ca_cert.is_none() && cfg.opentel_client_cert.is_none() {
+        return Ok(None);
+    }
+    let mut tls_config = ClientTlsConfig::new();
+    if let Some(ca_cert) = &cfg.opentel_ca_cert {
+        tls_config = tls_config.ca_certificate(Certificate::from_pem(read(ca_cert)?));
+    }
+    if let (Some(cert), Some(key)) = (&cfg.opentel_client_cert, &cfg.opentel_client_key) {
+        tls_config = tls_config.identity(Identity::from_pem(read(cert)?, read(key)?));
+    }
+    Ok(Some(tls_config))
+}
+
+fn http_client(cfg: &Configuration) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(ca_cert) = &cfg.opentel_ca_cert {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&read(ca_cert)?).map_err(|e| e.to_string())?);
+    }
+    if let (Some(cert), Some(key)) = (&cfg.opentel_client_cert, &cfg.opentel_client_key) {
+        builder = builder.identity(reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?).map_err(|e| e.to_string())?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// A client for the OTLP signals, which are not exported by `opentelemetry-otlp`.
+#[derive(Clone)]
+pub enum OtlpClient {
+    Grpc { channel: Channel, metadata: MetadataMap },
+    Http { client: reqwest::Client, endpoint: String, headers: HashMap<String, String> },
+}
+
+impl OtlpClient {
+    pub fn new(cfg: &Configuration, endpoint: &str) -> std::io::Result<Self> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        let headers = cfg.opentel_headers.as_deref().cloned().unwrap_or_default();
+        match cfg.opentel_protocol {
+            OpentelProtocol::Grpc => {
+                let mut channel = Endpoint::from_shared(endpoint.to_string())
+                    .map_err(|e| invalid(format!("Invalid OTLP endpoint {}: {}", endpoint, e)))?;
+                if let Some(tls_config) = tonic_tls_config(cfg, endpoint).map_err(invalid)? {
+                    channel = channel.tls_config(tls_config).map_err(|e| invalid(e.to_string()))?;
+                }
+                Ok(OtlpClient::Grpc { channel: channel.connect_lazy(), metadata: metadata(&headers).map_err(invalid)? })
+            }
+            OpentelProtocol::HttpProtobuf => Ok(OtlpClient::Http {
+                client: http_client(cfg).map_err(invalid)?,
+                endpoint: endpoint.trim_end_matches('/').to_string(),
+                headers,
+            }),
+        }
+    }
+
+    pub async fn export_logs(&self, request: ExportLogsServiceRequest) -> Result<(), String> {
+        match self {
+            OtlpClient::Grpc { channel, metadata } => {
+                let mut request = tonic::Request::new(request);
+                *request.metadata_mut() = metadata.clone();
+                LogsServiceClient::new(channel.clone()).export(request).await.map(|_| ()).map_err(|e| e.to_string())
+            }
+            OtlpClient::Http { .. } => self.post("/v1/logs", request.encode_to_vec()).await.map(|_| ()),
+        }
+    }
+
+    /// Exports the metrics, data points rejected by the receiver are an error as well.
+    pub async fn export_metrics(&self, request: ExportMetricsServiceRequest) -> Result<(), String> {
+        let response = match self {
+            OtlpClient::Grpc { channel, metadata } => {
+                let mut request = tonic::Request::new(request);
+                *request.metadata_mut() = metadata.clone();
+                MetricsServiceClient::new(channel.clone()).export(request).await.map_err(|e| e.to_string())?.into_inner()
+            }
+            OtlpClient::Http { .. } => {
+                let body = self.post("/v1/metrics", request.encode_to_vec()).await?;
+                ExportMetricsServiceResponse::decode(body.as_slice()).unwrap_or_default()
+            }
+        };
+        match response.partial_success {
+            Some(partial) if partial.rejected_data_points > 0 => Err(format!("{} data points rejected: {}", partial.rejected_data_points, partial.error_message)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn post(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (client, endpoint, headers) = match self {
+            OtlpClient::Http { client, endpoint, headers } => (client, endpoint, headers),
+            OtlpClient::Grpc { .. } => unreachable!("gRPC clients do not post"),
+        };
+        let mut request = client
+            .post(format!("{}{}", endpoint, path))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+            .body(body);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, String::from_utf8_lossy(&body)));
+        }
+        Ok(body.to_vec())
+    }
+}
@@ -0,0 +1,189 @@
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+use tokio::{fs::File, io::AsyncWriteExt, sync::watch};
+
+use super::{flush_until_terminated, unix_nanos, Batch};
+
+use crate::logprocessor::{BaseLog, HttpLogDetails};
+use crate::{Configuration, Telemetry};
+
+enum Destination {
+    Http { client: reqwest::Client, url: String, token: Option<String> },
+    File(tokio::sync::Mutex<File>),
+}
+
+/// Writes a point per request in the InfluxDB line protocol, in batches.
+pub struct InfluxDbWriter {
+    destination: Destination,
+    measurement: String,
+    common_tags: Vec<(String, String)>,
+    flush_interval: u64,
+    batch: Batch<String>,
+}
+
+impl InfluxDbWriter {
+    pub async fn new(cfg: &Configuration) -> std::io::Result<Option<Self>> {
+        let destination = match (&cfg.influxdb_url, &cfg.influxdb_file) {
+            (Some(url), _) => {
+                info!("Writing request points to InfluxDB at {}", url);
+                Destination::Http { client: reqwest::Client::new(), url: url.clone(), token: cfg.influxdb_token.as_deref().cloned() }
+            }
+            (None, Some(path)) => {
+                info!("Writing request points to {}", path);
+                let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+                Destination::File(tokio::sync::Mutex::new(file))
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let mut common_tags: Vec<(String, String)> = cfg.common_labels.clone().unwrap_or_default().into_iter().collect();
+        common_tags.sort();
+
+        Ok(Some(InfluxDbWriter {
+            destination,
+            measurement: cfg.influxdb_measurement.clone(),
+            common_tags,
+            flush_interval: cfg.influxdb_flush_interval,
+            batch: Batch::new(cfg.influxdb_batch_size),
+        }))
+    }
+
+    pub async fn http_log(&self, log: &BaseLog, http: &HttpLogDetails, metric_obj: &Telemetry) {
+        if self.batch.push(self.line(log, http)) {
+            self.flush(metric_obj).await;
+        }
+    }
+
+    /// The point of the request in the line protocol.
+    fn line(&self, log: &BaseLog, http: &HttpLogDetails) -> String {
+        let status = http.http_info.status.to_string();
+        let query = http.operation.query.as_ref();
+        let operation = query.and_then(|q| q.operation_name.as_deref()).unwrap_or("");
+        let error = http.operation.error.as_ref().map_or("", |e| e.code.as_str());
+
+        let mut line = escape(&self.measurement, ", ");
+        let tags = self.common_tags.iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .chain([("url", http.http_info.url.as_str()), ("status", &status), ("operation", operation), ("error", error)]);
+        for (name, value) in tags.filter(|(_, v)| !v.is_empty()) {
+            line.push_str(&format!(",{}={}", escape(name, ",= "), escape(value, ",= ")));
+        }
+
+        let mut fields = vec![
+            format!("request_id=\"{}\"", escape(&http.request_id, "\"\\")),
+            format!("response_size={}i", http.operation.response_size),
+        ];
+        if let Some(exec_time) = http.operation.query_execution_time {
+            fields.push(format!("execution_time={}", exec_time));
+        }
+        line.push(' ');
+        line.push_str(&fields.join(","));
+        line.push(' ');
+        // The time of the log line, so replayed logs keep their time
+        let time = log.time().unwrap_or_else(SystemTime::now);
+        line.push_str(&unix_nanos(time).to_string());
+        line
+    }
+
+    /// Flushes the batch periodically and a last time on termination.
+    pub async fn run(&self, metric_obj: &Telemetry, termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+        flush_until_terminated(Duration::from_millis(self.flush_interval), termination_rx, || self.flush(metric_obj)).await
+    }
+
+    async fn flush(&self, metric_obj: &Telemetry) {
+        let lines = self.batch.take();
+        if lines.is_empty() {
+            return;
+        }
+        debug!("Writing {} points to InfluxDB", lines.len());
+        let mut body = lines.join("\n");
+        body.push('\n');
+
+        let result = match &self.destination {
+            Destination::Http { client, url, token } => {
+                let mut request = client.post(url).body(body);
+                if let Some(token) = token {
+                    request = request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token));
+                }
+                match request.send().await {
+                    Ok(response) if response.status().is_success() => Ok(()),
+                    Ok(response) => {
+                        let status = response.status();
+                        Err(format!("{}: {}", status, response.text().await.unwrap_or_default()))
+                    }
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            Destination::File(file) => {
+                let mut file = file.lock().await;
+                match file.write_all(body.as_bytes()).await {
+                    Ok(()) => file.flush().await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to write {} points to InfluxDB: {}", lines.len(), e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["influxdb"]).inc();
+        }
+    }
+}
+
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn writer() -> InfluxDbWriter {
+        InfluxDbWriter {
+            destination: Destination::Http { client: reqwest::Client::new(), url: "http://localhost:8086".to_string(), token: None },
+            measurement: "hasura requests".to_string(),
+            common_tags: vec![("env".to_string(), "a=b".to_string())],
+            flush_interval: 1000,
+            batch: Batch::new(1),
+        }
+    }
+
+    #[test]
+    fn escapes_the_special_characters() {
+        assert_eq!(escape("a b,c=d", ",= "), "a\\ b\\,c\\=d");
+        assert_eq!(escape("say \"hi\" \\", "\"\\"), "say \\\"hi\\\" \\\\");
+    }
+
+    #[test]
+    fn line_of_a_request() {
+        let log: BaseLog = serde_json::from_value(json!({
+            "timestamp": "2024-01-01T00:00:01.5+0000",
+            "level": "info",
+            "type": "http-log",
+            "detail": {},
+        })).unwrap();
+        let http: HttpLogDetails = serde_json::from_value(json!({
+            "request_id": "r\"1",
+            "operation": {
+                "query_execution_time": 0.25,
+                "response_size": 42,
+                "query": { "operationName": "get orders" },
+            },
+            "http_info": { "status": 200, "http_version": "HTTP/1.1", "url": "/v1/graphql", "method": "POST", "ip": "127.0.0.1" },
+        })).unwrap();
+        assert_eq!(
+            writer().line(&log, &http),
+            "hasura\\ requests,env=a\\=b,url=/v1/graphql,status=200,operation=get\\ orders request_id=\"r\\\"1\",response_size=42i,execution_time=0.25 1704067201500000000",
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use opentelemetry::trace::SpanContext;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, KeyValue};
+use tokio::{sync::watch, time};
+use std::borrow::Cow;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logprocessor::{BaseLog, HttpLogDetails, WebSocketDetail};
+use crate::redaction::Redaction;
+use crate::{Configuration, OpentelSignal, Telemetry};
+
+mod influxdb;
+mod otlp_logs;
+mod otlp_metrics;
+mod pushgateway;
+mod remote_write;
+pub mod statsd;
+
+/// Runs the outputs pushing the metrics to other systems, each of them only
+/// when configured.
+pub(crate) async fn run_metric_outputs(cfg: &Configuration, metric_obj: &Telemetry, log_outputs: &LogOutputs, termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+    tokio::try_join!(
+        log_outputs.run(metric_obj, termination_rx.clone()),
+        otlp_metrics::push_metrics(cfg, metric_obj, termination_rx.clone()),
+        remote_write::push_metrics(cfg, metric_obj, termination_rx.clone()),
+        pushgateway::push_metrics(cfg, metric_obj, termination_rx.clone()),
+    )?;
+    Ok(())
+}
+
+/// The outputs receiving every processed log event, rather than the aggregated
+/// metrics.
+pub struct LogOutputs {
+    redaction: Redaction,
+    statsd: Option<statsd::StatsdClient>,
+    influxdb: Option<influxdb::InfluxDbWriter>,
+    otlp_logs: Option<otlp_logs::OtlpLogExporter>,
+}
+
+impl LogOutputs {
+    pub async fn new(cfg: &Configuration) -> std::io::Result<Self> {
+        let statsd = match &cfg.statsd_address {
+            Some(address) => Some(statsd::StatsdClient::new(cfg, address).await?),
+            None => None,
+        };
+        let influxdb = influxdb::InfluxDbWriter::new(cfg).await?;
+        let otlp_logs = match &cfg.opentel_addr {
+            Some(endpoint) if cfg.opentel_signals.contains(&OpentelSignal::Logs) => Some(otlp_logs::OtlpLogExporter::new(cfg, endpoint)?),
+            _ => None,
+        };
+        Ok(LogOutputs { redaction: Redaction::new(cfg), statsd, influxdb, otlp_logs })
+    }
+
+    /// Flushes the outputs which write in batches.
+    async fn run(&self, metric_obj: &Telemetry, termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+        tokio::try_join!(
+            async {
+                match &self.influxdb {
+                    Some(influxdb) => influxdb.run(metric_obj, termination_rx.clone()).await,
+                    None => Ok(()),
+                }
+            },
+            async {
+                match &self.otlp_logs {
+                    Some(otlp_logs) => otlp_logs.run(metric_obj, termination_rx.clone()).await,
+                    None => Ok(()),
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Scrubs the log line, before it is processed for any of the outputs.
+    pub fn redact<'a>(&self, logline: &'a str) -> Cow<'a, str> {
+        self.redaction.redact(logline)
+    }
+
+    /// Every log line, `span_context` is the span of the request the line is about.
+    pub async fn log(&self, logline: &str, log: &BaseLog, span_context: Option<&SpanContext>, metric_obj: &Telemetry) {
+        if let Some(otlp_logs) = &self.otlp_logs {
+            otlp_logs.log(logline, log, span_context, metric_obj).await;
+        }
+    }
+
+    pub async fn http_log(&self, log: &BaseLog, http: &HttpLogDetails, metric_obj: &Telemetry) {
+        if let Some(statsd) = &self.statsd {
+            statsd.http_log(http, metric_obj).await;
+        }
+        if let Some(influxdb) = &self.influxdb {
+            influxdb.http_log(log, http, metric_obj).await;
+        }
+    }
+
+    pub async fn websocket_log(&self, websocket: &WebSocketDetail, metric_obj: &Telemetry) {
+        if let Some(statsd) = &self.statsd {
+            statsd.websocket_log(websocket, metric_obj).await;
+        }
+    }
+}
+
+/// The items queued by an output which writes in batches.
+struct Batch<T> {
+    items: Mutex<Vec<T>>,
+    max_size: usize,
+}
+
+impl<T> Batch<T> {
+    fn new(max_size: usize) -> Self {
+        Batch { items: Mutex::new(Vec::new()), max_size: max_size.max(1) }
+    }
+
+    /// Queues the item, returns whether the batch is full and must be flushed.
+    fn push(&self, item: T) -> bool {
+        let mut items = self.items.lock().unwrap();
+        items.push(item);
+        items.len() >= self.max_size
+    }
+
+    /// Empties the batch.
+    fn take(&self) -> Vec<T> {
+        std::mem::take(&mut *self.items.lock().unwrap())
+    }
+}
+
+/// Flushes the batch of an output periodically and a last time on termination.
+async fn flush_until_terminated<F: Future<Output = ()>>(period: Duration, mut termination_rx: watch::Receiver<()>, flush: impl Fn() -> F) -> std::io::Result<()> {
+    let mut interval = time::interval(period);
+    loop {
+        tokio::select! {
+            biased;
+            _ = termination_rx.changed() => {
+                flush().await;
+                return Ok(())
+            },
+            _ = interval.tick() => flush().await,
+        }
+    }
+}
+
+fn key_value(key: &str, value: any_value::Value) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or_default()
+}
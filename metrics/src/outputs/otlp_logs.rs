@@ -0,0 +1,139 @@
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+use opentelemetry::trace::SpanContext;
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs, SeverityNumber};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use tokio::sync::watch;
+
+use super::{flush_until_terminated, key_value, unix_nanos, Batch};
+use crate::logprocessor::BaseLog;
+use crate::opentel::{self, OtlpClient};
+use crate::{Configuration, Telemetry};
+
+// Same as the batch span processor
+const MAX_BATCH_SIZE: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(5000);
+
+/// Exports every Hasura log line as an OTLP log record, in batches.
+pub struct OtlpLogExporter {
+    client: OtlpClient,
+    resource: Resource,
+    batch: Batch<LogRecord>,
+}
+
+impl OtlpLogExporter {
+    pub fn new(cfg: &Configuration, endpoint: &str) -> std::io::Result<Self> {
+        info!("Exporting logs to {} via {:?}", endpoint, cfg.opentel_protocol);
+
+        let attributes = opentel::resource_attributes(&cfg.common_labels.clone().unwrap_or_default())
+            .into_iter()
+            .map(|(k, v)| key_value(&k, any_value::Value::StringValue(v)))
+            .collect();
+        Ok(OtlpLogExporter {
+            client: OtlpClient::new(cfg, endpoint)?,
+            resource: Resource { attributes, dropped_attributes_count: 0 },
+            batch: Batch::new(MAX_BATCH_SIZE),
+        })
+    }
+
+    /// Queues the log line, `span_context` is the span of the request the line is about.
+    pub async fn log(&self, logline: &str, log: &BaseLog, span_context: Option<&SpanContext>, metric_obj: &Telemetry) {
+        let (severity_number, severity_text) = severity(&log.level);
+        let observed = unix_nanos(SystemTime::now());
+
+        let mut attributes = vec![key_value("hasura.log.type", any_value::Value::StringValue(log.logtype.clone()))];
+        flatten("", &log.detail, &mut attributes);
+
+        let mut record = LogRecord {
+            time_unix_nano: log.time().map(unix_nanos).unwrap_or(observed),
+            observed_time_unix_nano: observed,
+            severity_number: severity_number as i32,
+            severity_text,
+            body: Some(AnyValue { value: Some(any_value::Value::StringValue(logline.to_string())) }),
+            attributes,
+            ..Default::default()
+        };
+        if let Some(span_context) = span_context.filter(|c| c.is_valid()) {
+            record.trace_id = span_context.trace_id().to_bytes().to_vec();
+            record.span_id = span_context.span_id().to_bytes().to_vec();
+            record.flags = span_context.trace_flags().to_u8() as u32;
+        }
+
+        if self.batch.push(record) {
+            self.flush(metric_obj).await;
+        }
+    }
+
+    /// Flushes the batch periodically and a last time on termination.
+    pub async fn run(&self, metric_obj: &Telemetry, termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+        flush_until_terminated(FLUSH_INTERVAL, termination_rx, || self.flush(metric_obj)).await
+    }
+
+    async fn flush(&self, metric_obj: &Telemetry) {
+        let log_records = self.batch.take();
+        if log_records.is_empty() {
+            return;
+        }
+        debug!("Exporting {} log records", log_records.len());
+
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(self.resource.clone()),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope {
+                        name: env!("CARGO_PKG_NAME").to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        ..Default::default()
+                    }),
+                    log_records,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        };
+
+        if let Err(e) = self.client.export_logs(request).await {
+            warn!("Failed to export logs: {}", e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["otlp_logs"]).inc();
+        }
+    }
+}
+
+fn severity(level: &str) -> (SeverityNumber, String) {
+    let number = match level.to_lowercase().as_str() {
+        "debug" => SeverityNumber::Debug,
+        "info" => SeverityNumber::Info,
+        "warn" | "warning" => SeverityNumber::Warn,
+        "error" => SeverityNumber::Error,
+        "fatal" => SeverityNumber::Fatal,
+        _ => SeverityNumber::Unspecified,
+    };
+    (number, level.to_string())
+}
+
+/// Adds the values of the log detail as attributes, nested keys are joined by `.`
+/// and arrays are kept as JSON.
+fn flatten(prefix: &str, value: &serde_json::Value, attributes: &mut Vec<KeyValue>) {
+    let key = if prefix.is_empty() { "detail" } else { prefix };
+    let value = match value {
+        serde_json::Value::Object(map) => {
+            for (name, value) in map {
+                let key = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+                flatten(&key, value, attributes);
+            }
+            return;
+        }
+        serde_json::Value::Null => return,
+        serde_json::Value::Bool(b) => any_value::Value::BoolValue(*b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => any_value::Value::IntValue(i),
+            None => any_value::Value::DoubleValue(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => any_value::Value::StringValue(s.clone()),
+        serde_json::Value::Array(_) => any_value::Value::StringValue(value.to_string()),
+    };
+    attributes.push(key_value(key, value));
+}
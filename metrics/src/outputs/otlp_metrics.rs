@@ -0,0 +1,264 @@
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    exemplar, exponential_histogram_data_point::Buckets, metric::Data, number_data_point, AggregationTemporality,
+    Exemplar, ExponentialHistogram, ExponentialHistogramDataPoint, Gauge, Histogram, HistogramDataPoint, Metric,
+    NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use prometheus::proto::{self, LabelPair, MetricFamily, MetricType};
+use tokio::{sync::watch, time};
+
+use super::{key_value, unix_nanos};
+use crate::histogram::{NativeHistogram, NativeHistogramVec};
+use crate::telemetry::{self, HistogramExemplars};
+use crate::opentel::{self, OtlpClient};
+use crate::{Configuration, Telemetry};
+
+pub(crate) async fn push_metrics(cfg: &Configuration, metric_obj: &Telemetry, mut termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+    let endpoint = match &cfg.otlp_metrics_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(()),
+    };
+    let client = OtlpClient::new(cfg, endpoint)?;
+
+    info!("Pushing metrics to OTLP endpoint {} every {}ms", endpoint, cfg.otlp_metrics_interval);
+
+    // Counters and histograms are cumulative since the adapter started
+    let start_time = unix_nanos(SystemTime::now());
+    let period = Duration::from_millis(cfg.otlp_metrics_interval);
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+
+    loop {
+        tokio::select! {
+            biased;
+            _ = termination_rx.changed() => {
+                // Do not lose what happened since the last push
+                export(&client, metric_obj, start_time).await;
+                return Ok(())
+            },
+
+            _ = interval.tick() => export(&client, metric_obj, start_time).await,
+        }
+    }
+}
+
+async fn export(client: &OtlpClient, metric_obj: &Telemetry, start_time: u64) {
+    debug!("Pushing metrics to OTLP endpoint");
+    let request = ExportMetricsServiceRequest {
+        resource_metrics: vec![resource_metrics(metric_obj, start_time)],
+    };
+    if let Err(e) = client.export_metrics(request).await {
+        warn!("Failed to push metrics to OTLP endpoint: {}", e);
+        metric_obj.ERRORS_TOTAL.with_label_values(&["otlp_metrics"]).inc();
+    }
+}
+
+/// Converts everything gathered from the registries to OTLP metrics. The common
+/// labels become resource attributes instead of data point attributes.
+fn resource_metrics(metric_obj: &Telemetry, start_time: u64) -> ResourceMetrics {
+    let time = unix_nanos(SystemTime::now());
+
+    let attributes = opentel::resource_attributes(&metric_obj.common_labels)
+        .iter()
+        .map(|(k, v)| key_value(k, any_value::Value::StringValue(v.to_string())))
+        .collect();
+
+    let points = DataPoints {
+        metric_obj,
+        natives: metric_obj.native_histograms(),
+        exemplars: metric_obj.exemplars(),
+        start_time,
+        time,
+    };
+    let metrics = metric_obj.gather_all().iter().filter_map(|family| points.metric(family)).collect();
+
+    ResourceMetrics {
+        resource: Some(Resource { attributes, dropped_attributes_count: 0 }),
+        scope_metrics: vec![ScopeMetrics {
+            scope: Some(InstrumentationScope {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                ..Default::default()
+            }),
+            metrics,
+            schema_url: String::new(),
+        }],
+        schema_url: String::new(),
+    }
+}
+
+struct DataPoints<'a> {
+    metric_obj: &'a Telemetry,
+    natives: Vec<&'a NativeHistogramVec>,
+    exemplars: Vec<&'a HistogramExemplars>,
+    start_time: u64,
+    time: u64,
+}
+
+impl DataPoints<'_> {
+    fn metric(&self, family: &MetricFamily) -> Option<Metric> {
+        let name = family.get_name();
+        let data = match family.get_field_type() {
+            MetricType::COUNTER => Data::Sum(Sum {
+                data_points: family.get_metric().iter().map(|m| self.number(m, m.get_counter().get_value())).collect(),
+                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                is_monotonic: true,
+            }),
+            MetricType::GAUGE => Data::Gauge(Gauge {
+                data_points: family.get_metric().iter().map(|m| self.number(m, m.get_gauge().get_value())).collect(),
+            }),
+            MetricType::UNTYPED => Data::Gauge(Gauge {
+                data_points: family.get_metric().iter().map(|m| self.number(m, m.get_untyped().get_value())).collect(),
+            }),
+            MetricType::HISTOGRAM => match self.natives.iter().find(|n| n.name() == name) {
+                Some(native) => Data::ExponentialHistogram(ExponentialHistogram {
+                    data_points: family.get_metric().iter()
+                        .filter_map(|m| native.get(m.get_label()).map(|h| self.exponential_histogram(name, m, &h)))
+                        .collect(),
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                }),
+                None => Data::Histogram(Histogram {
+                    data_points: family.get_metric().iter().map(|m| self.histogram(name, m)).collect(),
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                }),
+            },
+            MetricType::SUMMARY => return None,
+        };
+
+        Some(Metric {
+            name: name.to_string(),
+            description: family.get_help().to_string(),
+            unit: unit(name).to_string(),
+            data: Some(data),
+        })
+    }
+
+    fn number(&self, metric: &proto::Metric, value: f64) -> NumberDataPoint {
+        NumberDataPoint {
+            attributes: self.attributes(metric.get_label()),
+            start_time_unix_nano: self.start_time,
+            time_unix_nano: self.time,
+            value: Some(number_data_point::Value::AsDouble(value)),
+            ..Default::default()
+        }
+    }
+
+    fn histogram(&self, name: &str, metric: &proto::Metric) -> HistogramDataPoint {
+        let histogram = metric.get_histogram();
+
+        // Prometheus buckets are cumulative, OTLP counts per bucket with an
+        // implicit +Inf bucket at the end.
+        let mut explicit_bounds = Vec::new();
+        let mut bucket_counts = Vec::new();
+        let mut previous = 0;
+        for bucket in histogram.get_bucket().iter().filter(|b| b.get_upper_bound().is_finite()) {
+            explicit_bounds.push(bucket.get_upper_bound());
+            bucket_counts.push(bucket.get_cumulative_count().saturating_sub(previous));
+            previous = bucket.get_cumulative_count();
+        }
+        bucket_counts.push(histogram.get_sample_count().saturating_sub(previous));
+
+        HistogramDataPoint {
+            attributes: self.attributes(metric.get_label()),
+            start_time_unix_nano: self.start_time,
+            time_unix_nano: self.time,
+            count: histogram.get_sample_count(),
+            sum: Some(histogram.get_sample_sum()),
+            bucket_counts,
+            explicit_bounds,
+            exemplars: self.exemplars(name, metric.get_label()),
+            ..Default::default()
+        }
+    }
+
+    fn exponential_histogram(&self, name: &str, metric: &proto::Metric, histogram: &NativeHistogram) -> ExponentialHistogramDataPoint {
+        ExponentialHistogramDataPoint {
+            attributes: self.attributes(metric.get_label()),
+            start_time_unix_nano: self.start_time,
+            time_unix_nano: self.time,
+            count: histogram.count,
+            sum: Some(histogram.sum),
+            scale: histogram.schema,
+            zero_count: histogram.zero_count,
+            positive: Some(buckets(&histogram.positive)),
+            negative: Some(buckets(&histogram.negative)),
+            exemplars: self.exemplars(name, metric.get_label()),
+            ..Default::default()
+        }
+    }
+
+    /// The labels of a series without the common labels, which are part of the resource.
+    fn attributes(&self, labels: &[LabelPair]) -> Vec<KeyValue> {
+        labels.iter()
+            .filter(|l| !self.metric_obj.common_labels.contains_key(l.get_name()))
+            .map(|l| key_value(l.get_name(), any_value::Value::StringValue(l.get_value().to_string())))
+            .collect()
+    }
+
+    fn exemplars(&self, name: &str, labels: &[LabelPair]) -> Vec<Exemplar> {
+        let exemplars = match self.exemplars.iter().find(|e| e.name == name) {
+            Some(exemplars) => exemplars.get(labels),
+            None => return vec![],
+        };
+        let mut exemplars: Vec<&telemetry::Exemplar> = exemplars.values().collect();
+        exemplars.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+
+        exemplars.into_iter()
+            .map(|e| Exemplar {
+                filtered_attributes: e.labels.iter()
+                    .filter(|(k, _)| k != "trace_id")
+                    .map(|(k, v)| key_value(k, any_value::Value::StringValue(v.to_string())))
+                    .collect(),
+                time_unix_nano: (e.timestamp * 1e9) as u64,
+                trace_id: e.labels.iter()
+                    .find(|(k, _)| k == "trace_id")
+                    .and_then(|(_, v)| hex_decode(v))
+                    .unwrap_or_default(),
+                value: Some(exemplar::Value::AsDouble(e.value)),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+/// Prometheus bucket `i` covers `(base^(i-1), base^i]`, OTLP bucket `i` covers
+/// `(base^i, base^(i+1)]`, hence the offset is one less. Unlike the sparse
+/// Prometheus representation the OTLP counts are dense.
+fn buckets(buckets: &std::collections::BTreeMap<i32, u64>) -> Buckets {
+    let (first, last) = match (buckets.keys().next(), buckets.keys().next_back()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return Buckets::default(),
+    };
+    Buckets {
+        offset: first - 1,
+        bucket_counts: (first..=last).map(|i| buckets.get(&i).copied().unwrap_or_default()).collect(),
+    }
+}
+
+/// The UCUM unit of a metric, derived from the Prometheus unit suffix.
+fn unit(name: &str) -> &'static str {
+    let name = name.trim_end_matches("_total");
+    if name.ends_with("_seconds") {
+        "s"
+    } else if name.ends_with("_bytes") {
+        "By"
+    } else if name.ends_with("_ratio") {
+        "1"
+    } else {
+        ""
+    }
+}
+
+fn hex_decode(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 == 1 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
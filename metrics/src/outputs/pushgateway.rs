@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use prometheus::{Encoder, ProtobufEncoder};
+use tokio::{sync::watch, time};
+
+use crate::{Configuration, Telemetry};
+
+pub(crate) async fn push_metrics(cfg: &Configuration, metric_obj: &Telemetry, mut termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+    let url = match &cfg.pushgateway_url {
+        Some(url) => grouping_url(url, &cfg.pushgateway_job, &metric_obj.common_labels),
+        None => return Ok(()),
+    };
+    let client = reqwest::Client::new();
+
+    info!("Pushing metrics to Pushgateway group {}", url);
+
+    match cfg.pushgateway_interval {
+        Some(interval) => {
+            let period = Duration::from_millis(interval);
+            let mut interval = time::interval_at(time::Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = termination_rx.changed() => break,
+                    _ = interval.tick() => push(&client, &url, metric_obj).await,
+                }
+            }
+        }
+        None => {
+            let _ = termination_rx.changed().await;
+        }
+    }
+
+    // The final state is what remains after the adapter is gone
+    push(&client, &url, metric_obj).await;
+    Ok(())
+}
+
+async fn push(client: &reqwest::Client, url: &str, metric_obj: &Telemetry) {
+    debug!("Pushing metrics to Pushgateway");
+
+    // The common labels are part of the grouping key, the Pushgateway adds them
+    let mut families = metric_obj.gather_all();
+    for family in families.iter_mut() {
+        for metric in family.mut_metric().iter_mut() {
+            metric.mut_label().retain(|l| !metric_obj.common_labels.contains_key(l.get_name()));
+        }
+    }
+
+    let encoder = ProtobufEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&families, &mut body) {
+        warn!("Failed to encode metrics for Pushgateway: {}", e);
+        metric_obj.ERRORS_TOTAL.with_label_values(&["pushgateway"]).inc();
+        return;
+    }
+
+    // PUT replaces all metrics of the group, so metrics which are gone are removed there as well
+    let result = client
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, encoder.format_type())
+        .body(body)
+        .send()
+        .await;
+    match result {
+        Ok(response) if response.status().is_success() => (),
+        Ok(response) => {
+            let status = response.status();
+            warn!("Push to Pushgateway failed with {}: {}", status, response.text().await.unwrap_or_default());
+            metric_obj.ERRORS_TOTAL.with_label_values(&["pushgateway"]).inc();
+        }
+        Err(e) => {
+            warn!("Push to Pushgateway failed: {}", e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["pushgateway"]).inc();
+        }
+    }
+}
+
+/// The URL of the group identified by the job and the common labels.
+fn grouping_url(base: &str, job: &str, common_labels: &HashMap<String, String>) -> String {
+    let mut url = format!("{}/metrics/{}", base.trim_end_matches('/'), path_segment("job", job));
+
+    let mut labels: Vec<(&String, &String)> = common_labels.iter().collect();
+    labels.sort();
+    for (name, value) in labels {
+        url.push('/');
+        url.push_str(&path_segment(name, value));
+    }
+    url
+}
+
+/// Values which are empty or not safe in a path are base64 encoded, as supported
+/// by the Pushgateway.
+fn path_segment(name: &str, value: &str) -> String {
+    let safe = !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+    if safe {
+        format!("{}/{}", name, value)
+    } else if value.is_empty() {
+        format!("{}@base64/=", name)
+    } else {
+        format!("{}@base64/{}", name, base64::encode_config(value, base64::URL_SAFE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_segment_encodes_unsafe_values() {
+        assert_eq!(path_segment("job", "hasura-metrics_1.0~x"), "job/hasura-metrics_1.0~x");
+        assert_eq!(path_segment("path", "/api/v1"), "path@base64/L2FwaS92MQ==");
+        assert_eq!(path_segment("region", "eu west"), "region@base64/ZXUgd2VzdA==");
+        assert_eq!(path_segment("env", ""), "env@base64/=");
+    }
+
+    #[test]
+    fn grouping_url_has_the_job_and_sorted_common_labels() {
+        let labels = HashMap::from([
+            ("zone".to_string(), "a".to_string()),
+            ("path".to_string(), "/api/v1".to_string()),
+            ("env".to_string(), "".to_string()),
+        ]);
+        assert_eq!(
+            grouping_url("http://pushgateway:9091/", "hasura", &labels),
+            "http://pushgateway:9091/metrics/job/hasura/env@base64/=/path@base64/L2FwaS92MQ==/zone/a",
+        );
+        assert_eq!(grouping_url("http://pushgateway:9091", "hasura", &HashMap::new()), "http://pushgateway:9091/metrics/job/hasura");
+    }
+}
@@ -0,0 +1,375 @@
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prost::Message;
+use reqwest::StatusCode;
+use tokio::{sync::watch, time};
+
+use crate::histogram::{self, NativeHistogram, NativeHistogramVec};
+use crate::{exposition, Configuration, Telemetry};
+
+// The messages of the Prometheus remote write 1.0 protocol (prompb).
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+    #[prost(message, repeated, tag = "3")]
+    metadata: Vec<MetricMetadata>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    exemplars: Vec<Exemplar>,
+    #[prost(message, repeated, tag = "4")]
+    histograms: Vec<Histogram>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Exemplar {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(double, tag = "2")]
+    value: f64,
+    #[prost(int64, tag = "3")]
+    timestamp: i64,
+}
+
+/// A native histogram, only the integer variants of the count fields are used.
+#[derive(Clone, PartialEq, Message)]
+struct Histogram {
+    #[prost(uint64, tag = "1")]
+    count_int: u64,
+    #[prost(double, tag = "3")]
+    sum: f64,
+    #[prost(sint32, tag = "4")]
+    schema: i32,
+    #[prost(double, tag = "5")]
+    zero_threshold: f64,
+    #[prost(uint64, tag = "6")]
+    zero_count_int: u64,
+    #[prost(message, repeated, tag = "8")]
+    negative_spans: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "9")]
+    negative_deltas: Vec<i64>,
+    #[prost(message, repeated, tag = "11")]
+    positive_spans: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "12")]
+    positive_deltas: Vec<i64>,
+    #[prost(int64, tag = "15")]
+    timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct BucketSpan {
+    #[prost(sint32, tag = "1")]
+    offset: i32,
+    #[prost(uint32, tag = "2")]
+    length: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MetricMetadata {
+    #[prost(int32, tag = "1")]
+    metric_type: i32,
+    #[prost(string, tag = "2")]
+    metric_family_name: String,
+    #[prost(string, tag = "4")]
+    help: String,
+    #[prost(string, tag = "5")]
+    unit: String,
+}
+
+pub(crate) async fn push_metrics(cfg: &Configuration, metric_obj: &Telemetry, mut termination_rx: watch::Receiver<()>) -> std::io::Result<()> {
+    let url = match &cfg.remote_write_url {
+        Some(url) => url,
+        None => return Ok(()),
+    };
+    let client = reqwest::Client::new();
+
+    info!("Pushing metrics via remote write to {} every {}ms", url, cfg.remote_write_interval);
+
+    let period = Duration::from_millis(cfg.remote_write_interval);
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+
+    loop {
+        tokio::select! {
+            biased;
+            _ = termination_rx.changed() => {
+                // Do not lose what happened since the last push
+                push(cfg, &client, url, metric_obj, termination_rx.clone()).await;
+                return Ok(())
+            },
+
+            _ = interval.tick() => push(cfg, &client, url, metric_obj, termination_rx.clone()).await,
+        }
+    }
+}
+
+async fn push(cfg: &Configuration, client: &reqwest::Client, url: &str, metric_obj: &Telemetry, mut termination_rx: watch::Receiver<()>) {
+    let body = match snap::raw::Encoder::new().compress_vec(&write_request(metric_obj).encode_to_vec()) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to compress remote write request: {}", e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["remote_write"]).inc();
+            return;
+        }
+    };
+
+    let mut backoff = Duration::from_millis(cfg.remote_write_min_backoff);
+    let mut attempt = 0;
+    loop {
+        debug!("Pushing metrics via remote write");
+        let mut request = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+            .header(reqwest::header::CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .timeout(Duration::from_millis(cfg.remote_write_timeout))
+            .body(body.clone());
+        if let Some(username) = &cfg.remote_write_username {
+            request = request.basic_auth(username, cfg.remote_write_password.as_deref());
+        } else if let Some(token) = cfg.remote_write_bearer_token.as_deref() {
+            request = request.bearer_auth(token);
+        }
+
+        let retry = match request.send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => {
+                let status = response.status();
+                warn!("Remote write to {} failed with {}: {}", url, status, response.text().await.unwrap_or_default());
+                // Like Prometheus, only retry what may succeed later
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                warn!("Remote write to {} failed: {}", url, e);
+                true
+            }
+        };
+        metric_obj.ERRORS_TOTAL.with_label_values(&["remote_write"]).inc();
+
+        attempt += 1;
+        if !retry || attempt > cfg.remote_write_max_retries {
+            warn!("Dropping remote write request after {} attempts", attempt);
+            return;
+        }
+
+        tokio::select! {
+            biased;
+            _ = termination_rx.changed() => return,
+            _ = time::sleep(backoff) => (),
+        }
+        backoff = (backoff * 2).min(Duration::from_millis(cfg.remote_write_max_backoff));
+    }
+}
+
+/// Flattens the gathered families to time series the way Prometheus would have
+/// scraped them, with all samples at the current time.
+fn write_request(metric_obj: &Telemetry) -> WriteRequest {
+    let timestamp = (exposition::now() * 1000.0) as i64;
+    let natives = metric_obj.native_histograms();
+    let exemplars = metric_obj.exemplars();
+
+    let mut request = WriteRequest::default();
+    for family in metric_obj.gather_all() {
+        let name = family.get_name();
+        request.metadata.push(metadata(&family));
+
+        for metric in family.get_metric() {
+            let sample = |suffix: &str, extra_label: Option<(&str, String)>, value: f64, exemplar: Option<Exemplar>| TimeSeries {
+                labels: labels(&format!("{}{}", name, suffix), metric.get_label(), extra_label),
+                samples: vec![Sample { value, timestamp }],
+                exemplars: exemplar.into_iter().collect(),
+                histograms: vec![],
+            };
+            let series = &mut request.timeseries;
+
+            match family.get_field_type() {
+                MetricType::COUNTER => series.push(sample("", None, metric.get_counter().get_value(), None)),
+                MetricType::GAUGE => series.push(sample("", None, metric.get_gauge().get_value(), None)),
+                MetricType::UNTYPED => series.push(sample("", None, metric.get_untyped().get_value(), None)),
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        series.push(sample("", Some(("quantile", quantile.get_quantile().to_string())), quantile.get_value(), None));
+                    }
+                    series.push(sample("_sum", None, summary.get_sample_sum(), None));
+                    series.push(sample("_count", None, summary.get_sample_count() as f64, None));
+                }
+                MetricType::HISTOGRAM => {
+                    let bucket_exemplars = exemplars.iter()
+                        .find(|e| e.name == name)
+                        .map(|e| e.get(metric.get_label()))
+                        .unwrap_or_default();
+
+                    let native = natives.iter().find(|n| n.name() == name);
+                    if let Some((native, histogram)) = native.and_then(|n| n.get(metric.get_label()).map(|h| (n, h))) {
+                        series.push(TimeSeries {
+                            labels: labels(name, metric.get_label(), None),
+                            exemplars: bucket_exemplars.values().map(exemplar).collect(),
+                            histograms: vec![native_histogram(native, &histogram, timestamp)],
+                            ..Default::default()
+                        });
+                        continue;
+                    }
+
+                    let histogram = metric.get_histogram();
+                    let buckets: Vec<_> = histogram.get_bucket().iter().filter(|b| b.get_upper_bound().is_finite()).collect();
+                    for (index, bucket) in buckets.iter().enumerate() {
+                        let le = bucket.get_upper_bound().to_string();
+                        series.push(sample("_bucket", Some(("le", le)), bucket.get_cumulative_count() as f64, bucket_exemplars.get(&index).map(exemplar)));
+                    }
+                    series.push(sample("_bucket", Some(("le", "+Inf".to_string())), histogram.get_sample_count() as f64, bucket_exemplars.get(&buckets.len()).map(exemplar)));
+                    series.push(sample("_sum", None, histogram.get_sample_sum(), None));
+                    series.push(sample("_count", None, histogram.get_sample_count() as f64, None));
+                }
+            }
+        }
+    }
+    request
+}
+
+fn labels(name: &str, label_pairs: &[LabelPair], extra_label: Option<(&str, String)>) -> Vec<Label> {
+    // An empty label value is the same as no label for Prometheus
+    let mut labels: Vec<Label> = label_pairs.iter()
+        .filter(|l| !l.get_value().is_empty())
+        .map(|l| Label { name: l.get_name().to_string(), value: l.get_value().to_string() })
+        .collect();
+    if let Some((label, value)) = extra_label {
+        labels.push(Label { name: label.to_string(), value });
+    }
+    labels.push(Label { name: "__name__".to_string(), value: name.to_string() });
+    // Receivers require the labels sorted by name
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels
+}
+
+fn exemplar(exemplar: &crate::telemetry::Exemplar) -> Exemplar {
+    Exemplar {
+        labels: exemplar.labels.iter().map(|(name, value)| Label { name: name.clone(), value: value.clone() }).collect(),
+        value: exemplar.value,
+        timestamp: (exemplar.timestamp * 1000.0) as i64,
+    }
+}
+
+fn native_histogram(native: &NativeHistogramVec, histogram: &NativeHistogram, timestamp: i64) -> Histogram {
+    let (negative_spans, negative_deltas) = histogram::spans_and_deltas(&histogram.negative);
+    let (positive_spans, positive_deltas) = histogram::spans_and_deltas(&histogram.positive);
+    let spans = |spans: Vec<(i32, u32)>| spans.into_iter().map(|(offset, length)| BucketSpan { offset, length }).collect();
+    Histogram {
+        count_int: histogram.count,
+        sum: histogram.sum,
+        schema: histogram.schema,
+        zero_threshold: native.zero_threshold(),
+        zero_count_int: histogram.zero_count,
+        negative_spans: spans(negative_spans),
+        negative_deltas,
+        positive_spans: spans(positive_spans),
+        positive_deltas,
+        timestamp,
+    }
+}
+
+fn metadata(family: &MetricFamily) -> MetricMetadata {
+    // prompb.MetricMetadata.MetricType
+    let metric_type = match family.get_field_type() {
+        MetricType::COUNTER => 1,
+        MetricType::GAUGE => 2,
+        MetricType::HISTOGRAM => 3,
+        MetricType::SUMMARY => 5,
+        MetricType::UNTYPED => 0,
+    };
+    MetricMetadata {
+        metric_type,
+        metric_family_name: family.get_name().to_string(),
+        help: family.get_help().to_string(),
+        unit: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::histogram::{HistogramConfig, HistogramType};
+    use crate::telemetry::MetricNaming;
+
+    fn telemetry() -> Telemetry {
+        let naming = MetricNaming { prefix: "hasura".to_string(), renames: HashMap::new(), dropped: vec![] };
+        let histograms = HistogramConfig {
+            histogram_type: HistogramType::Classic,
+            buckets: vec![0.1, 1.0],
+            metric_buckets: HashMap::new(),
+            native_schema: 3,
+            native_zero_threshold: 0.0,
+            native_max_buckets: 160,
+        };
+        Telemetry::new(HashMap::from([("env".to_string(), "test".to_string())]), naming, histograms, false).unwrap()
+    }
+
+    fn label_values(series: &TimeSeries) -> Vec<(&str, &str)> {
+        series.labels.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect()
+    }
+
+    #[test]
+    fn labels_are_sorted_without_empty_values() {
+        let mut operation = LabelPair::default();
+        operation.set_name("operation".to_string());
+        operation.set_value("q".to_string());
+        let mut error = LabelPair::default();
+        error.set_name("error".to_string());
+        let labels = labels("requests_bucket", &[operation, error], Some(("le", "1".to_string())));
+        let labels: Vec<(&str, &str)> = labels.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect();
+        assert_eq!(labels, [("__name__", "requests_bucket"), ("le", "1"), ("operation", "q")]);
+    }
+
+    #[test]
+    fn write_request_flattens_histograms() {
+        let metric_obj = telemetry();
+        metric_obj.QUERY_EXECUTION_TIMES.observe(&["q", ""], 0.5);
+        metric_obj.QUERY_EXECUTION_EXEMPLARS.observe(&["q", ""], 0.5, vec![("trace_id".to_string(), "abc".to_string())]);
+
+        let request = write_request(&metric_obj);
+        let series: Vec<&TimeSeries> = request.timeseries.iter()
+            .filter(|s| s.labels.iter().any(|l| l.name == "__name__" && l.value.starts_with("hasura_query_execution_seconds")))
+            .collect();
+        let samples: Vec<_> = series.iter()
+            .map(|s| (label_values(s), s.samples[0].value, s.exemplars.len()))
+            .collect();
+        assert_eq!(samples, [
+            (vec![("__name__", "hasura_query_execution_seconds_bucket"), ("env", "test"), ("le", "0.1"), ("operation", "q")], 0.0, 0),
+            (vec![("__name__", "hasura_query_execution_seconds_bucket"), ("env", "test"), ("le", "1"), ("operation", "q")], 1.0, 1),
+            (vec![("__name__", "hasura_query_execution_seconds_bucket"), ("env", "test"), ("le", "+Inf"), ("operation", "q")], 1.0, 0),
+            (vec![("__name__", "hasura_query_execution_seconds_sum"), ("env", "test"), ("operation", "q")], 0.5, 0),
+            (vec![("__name__", "hasura_query_execution_seconds_count"), ("env", "test"), ("operation", "q")], 1.0, 0),
+        ]);
+        assert_eq!(series[1].exemplars[0].labels[0].value, "abc");
+
+        let metadata = request.metadata.iter().find(|m| m.metric_family_name == "hasura_query_execution_seconds").unwrap();
+        assert_eq!(metadata.metric_type, 3);
+        assert!(metadata.help.starts_with("Query execution time."));
+    }
+}
@@ -0,0 +1,152 @@
+use log::{debug, info};
+use tokio::net::UdpSocket;
+
+use crate::logprocessor::{HttpLogDetails, WebSocketDetail};
+use crate::{Configuration, Telemetry};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsdFlavor {
+    /// Plain StatsD, without tags
+    Statsd,
+    /// StatsD with DogStatsD tags
+    Dogstatsd,
+}
+
+/// Emits every processed log event as StatsD counters and timings over UDP.
+pub struct StatsdClient {
+    socket: UdpSocket,
+    prefix: String,
+    flavor: StatsdFlavor,
+    common_tags: Vec<(String, String)>,
+}
+
+impl StatsdClient {
+    pub async fn new(cfg: &Configuration, address: &str) -> std::io::Result<Self> {
+        let target = tokio::net::lookup_host(address).await?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("StatsD address {} could not be resolved", address)))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+        socket.connect(target).await?;
+
+        info!("Emitting log events to StatsD at {}", address);
+
+        let mut common_tags: Vec<(String, String)> = cfg.common_labels.clone().unwrap_or_default().into_iter().collect();
+        common_tags.sort();
+
+        Ok(StatsdClient {
+            socket,
+            prefix: cfg.statsd_prefix.clone(),
+            flavor: cfg.statsd_flavor,
+            common_tags,
+        })
+    }
+
+    pub async fn http_log(&self, http: &HttpLogDetails, metric_obj: &Telemetry) {
+        let status = http.http_info.status.to_string();
+        let mut lines = vec![self.line("requests", "1", "c", &[("url", &http.http_info.url), ("status", &status)])];
+
+        if let Some(query) = &http.operation.query {
+            let operation = query.operation_name.as_deref().unwrap_or("");
+            let error = http.operation.error.as_ref().map_or("", |e| e.code.as_str());
+            let tags = [("operation", operation), ("error", error)];
+            lines.push(self.line("request_queries", "1", "c", &tags));
+            if let Some(exec_time) = http.operation.query_execution_time {
+                lines.push(self.line("query_execution_time", &(exec_time * 1000.0).to_string(), "ms", &tags));
+            }
+        }
+
+        self.send(&lines, metric_obj).await;
+    }
+
+    pub async fn websocket_log(&self, websocket: &WebSocketDetail, metric_obj: &Telemetry) {
+        let line = match (websocket.event.event_type.as_str(), &websocket.event.detail) {
+            ("accepted", _) => self.line("websocket_connections_opened", "1", "c", &[]),
+            ("closed", _) => self.line("websocket_connections_closed", "1", "c", &[]),
+            ("operation", Some(detail)) => {
+                let operation = detail.operation_name.as_deref().unwrap_or("");
+                match detail.operation_type.operation_type.as_str() {
+                    "started" => self.line("websocket_operations_started", "1", "c", &[("operation", operation)]),
+                    "stopped" => self.line("websocket_operations", "1", "c", &[("operation", operation), ("error", "")]),
+                    "query_err" => {
+                        let error = detail.operation_type.detail.as_ref().map_or("", |e| e.code.as_str());
+                        self.line("websocket_operations", "1", "c", &[("operation", operation), ("error", error)])
+                    }
+                    _ => return,
+                }
+            }
+            _ => return,
+        };
+
+        self.send(&[line], metric_obj).await;
+    }
+
+    fn line(&self, name: &str, value: &str, metric_type: &str, tags: &[(&str, &str)]) -> String {
+        let mut line = if self.prefix.is_empty() {
+            format!("{}:{}|{}", name, value, metric_type)
+        } else {
+            format!("{}.{}:{}|{}", self.prefix, name, value, metric_type)
+        };
+
+        if self.flavor == StatsdFlavor::Dogstatsd {
+            let tags: Vec<String> = self.common_tags.iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .chain(tags.iter().copied())
+                .filter(|(_, v)| !v.is_empty())
+                .map(|(k, v)| format!("{}:{}", k, escape(v)))
+                .collect();
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+        }
+        line
+    }
+
+    async fn send(&self, lines: &[String], metric_obj: &Telemetry) {
+        // All metrics of an event fit into a single datagram
+        if let Err(e) = self.socket.send(lines.join("\n").as_bytes()).await {
+            debug!("Failed to send to StatsD: {}", e);
+            metric_obj.ERRORS_TOTAL.with_label_values(&["statsd"]).inc();
+        }
+    }
+}
+
+/// Tag values must not contain the separators of the DogStatsD format.
+fn escape(value: &str) -> String {
+    value.replace(['|', ',', '#', '\n'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn client(prefix: &str, flavor: StatsdFlavor) -> StatsdClient {
+        StatsdClient {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            prefix: prefix.to_string(),
+            flavor,
+            common_tags: vec![("env".to_string(), "test".to_string())],
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_statsd_lines_have_no_tags() {
+        let unprefixed = client("", StatsdFlavor::Statsd).await;
+        assert_eq!(unprefixed.line("requests", "1", "c", &[("status", "200")]), "requests:1|c");
+        let prefixed = client("hasura", StatsdFlavor::Statsd).await;
+        assert_eq!(prefixed.line("query_execution_time", "12.5", "ms", &[]), "hasura.query_execution_time:12.5|ms");
+    }
+
+    #[tokio::test]
+    async fn dogstatsd_lines_carry_the_tags() {
+        let client = client("hasura", StatsdFlavor::Dogstatsd).await;
+        assert_eq!(
+            client.line("requests", "1", "c", &[("url", "/v1/graphql"), ("error", "")]),
+            "hasura.requests:1|c|#env:test,url:/v1/graphql",
+        );
+        assert_eq!(
+            client.line("request_queries", "1", "c", &[("operation", "a|b,c#d\ne")]),
+            "hasura.request_queries:1|c|#env:test,operation:a_b_c_d_e",
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use log::warn;
+use serde_json::Value;
+
+use crate::{jsonpath, Configuration};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedactAction {
+    /// Removes the field
+    Drop,
+    /// Replaces a string by its salted SHA-256, so equal values stay correlated. Other
+    /// values are dropped, a number or an object replaced by a string would no longer
+    /// deserialize
+    Hash,
+}
+
+/// A `path=action` rule, the path is a JSON path of object keys and array indices into
+/// the log line, e.g. `$.detail.http_info.ip`, where `*` matches every key or array element.
+#[derive(Clone, Debug)]
+pub struct RedactRule {
+    path: Vec<String>,
+    action: RedactAction,
+}
+
+impl FromStr for RedactRule {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (path, action) = input
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid PATH=action: no `=` found in `{}`", input))?;
+        let action = match action.trim() {
+            "drop" => RedactAction::Drop,
+            "hash" => RedactAction::Hash,
+            other => return Err(format!("invalid redaction action `{}`, expected drop or hash", other)),
+        };
+        let path = jsonpath::parse(path)?;
+        if path.is_empty() {
+            return Err(format!("invalid redaction path in `{}`, the whole line cannot be redacted", input));
+        }
+        Ok(RedactRule { path, action })
+    }
+}
+
+/// Scrubs the log lines before they are processed, so no output ever sees the
+/// redacted fields.
+pub struct Redaction {
+    rules: Vec<RedactRule>,
+    salt: String,
+}
+
+impl Redaction {
+    pub fn new(cfg: &Configuration) -> Self {
+        if cfg.redact_salt.is_none() && cfg.redact.iter().any(|rule| rule.action == RedactAction::Hash) {
+            warn!("Hashing redacted values without REDACT_SALT, the hashes of guessable values like IPs can be reversed");
+        }
+        Redaction {
+            rules: cfg.redact.clone(),
+            salt: cfg.redact_salt.as_deref().cloned().unwrap_or_default(),
+        }
+    }
+
+    /// The redacted log line, which is unchanged without rules or if it is no JSON.
+    pub fn redact<'a>(&self, logline: &'a str) -> Cow<'a, str> {
+        if self.rules.is_empty() {
+            return Cow::Borrowed(logline);
+        }
+        let mut log = match serde_json::from_str::<Value>(logline) {
+            Ok(log) => log,
+            Err(_) => return Cow::Borrowed(logline),
+        };
+        for rule in &self.rules {
+            self.apply(&mut log, &rule.path, rule.action);
+        }
+        Cow::Owned(log.to_string())
+    }
+
+    fn apply(&self, value: &mut Value, path: &[String], action: RedactAction) {
+        let (segment, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        match value {
+            Value::Object(map) if rest.is_empty() => {
+                let keys: Vec<String> = map.keys().filter(|key| segment == "*" || *key == segment).cloned().collect();
+                for key in keys {
+                    match (action, map.get(&key)) {
+                        (RedactAction::Hash, Some(Value::String(plain))) => {
+                            let hashed = self.hash(plain);
+                            map.insert(key, hashed);
+                        }
+                        _ => {
+                            map.remove(&key);
+                        }
+                    }
+                }
+            }
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if segment == "*" || key == segment {
+                        self.apply(value, rest, action);
+                    }
+                }
+            }
+            Value::Array(values) => {
+                let indices: Vec<usize> = match segment.as_str() {
+                    "*" => (0..values.len()).collect(),
+                    index => index.parse().ok().filter(|i| *i < values.len()).into_iter().collect(),
+                };
+                if !rest.is_empty() {
+                    indices.into_iter().for_each(|i| self.apply(&mut values[i], rest, action));
+                    return;
+                }
+                // Backwards, so the indices of the remaining elements stay valid
+                for i in indices.into_iter().rev() {
+                    match (action, &values[i]) {
+                        (RedactAction::Hash, Value::String(plain)) => values[i] = self.hash(plain),
+                        _ => {
+                            values.remove(i);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn hash(&self, plain: &str) -> Value {
+        let mut hasher = openssl::sha::Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update(plain.as_bytes());
+        let digest: String = hasher.finish().iter().map(|b| format!("{:02x}", b)).collect();
+        Value::String(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redaction(rules: &[&str]) -> Redaction {
+        Redaction {
+            rules: rules.iter().map(|rule| rule.parse().unwrap()).collect(),
+            salt: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: RedactRule = "$.detail.http_info.ip = hash".parse().unwrap();
+        assert_eq!(rule.path, ["detail", "http_info", "ip"]);
+        assert_eq!(rule.action, RedactAction::Hash);
+        // The path may contain `=`, the action follows the last one
+        assert_eq!("$.a=b.c=drop".parse::<RedactRule>().unwrap().path, ["a=b", "c"]);
+
+        assert!("$.detail".parse::<RedactRule>().unwrap_err().contains("no `=` found"));
+        assert!("$.detail=mask".parse::<RedactRule>().unwrap_err().contains("expected drop or hash"));
+        assert!("$=drop".parse::<RedactRule>().unwrap_err().contains("cannot be redacted"));
+        assert!("$.a..b=drop".parse::<RedactRule>().is_err());
+    }
+
+    #[test]
+    fn drops_fields() {
+        let redaction = redaction(&["$.detail.*.password=drop", "$.level=drop"]);
+        let redacted = redaction.redact(r#"{"level":"info","detail":{"a":{"password":"x","user":"u"},"b":{"password":"y"}}}"#);
+        assert_eq!(redacted, r#"{"detail":{"a":{"user":"u"},"b":{}}}"#);
+    }
+
+    #[test]
+    fn hashes_values_with_the_salt() {
+        let mut redaction = redaction(&["$.ip=hash"]);
+        redaction.salt = "s".to_string();
+        assert_eq!(
+            redaction.redact(r#"{"ip":"1.2.3.4"}"#),
+            r#"{"ip":"29be39f1826b7b8ca10209b12a101a97998e081b46d353a63d1ca701efcb3332"}"#,
+        );
+    }
+
+    #[test]
+    fn hashes_only_strings() {
+        let redaction = redaction(&["$.detail.*=hash", "$.ids[*]=hash"]);
+        let redacted = redaction.redact(r#"{"detail":{"status":200,"query":{"a":1},"ip":"1.2.3.4"},"ids":[1,"a"]}"#);
+        assert_eq!(
+            redacted,
+            r#"{"detail":{"ip":"6694f83c9f476da31f5df6bcc520034e7e57d421d247b9d34f49edbfc84a764c"},"ids":["ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"]}"#,
+        );
+    }
+
+    #[test]
+    fn keeps_lines_without_rules_or_json() {
+        assert!(matches!(redaction(&[]).redact("{\"a\":1}"), Cow::Borrowed(_)));
+        assert_eq!(redaction(&["$.a=drop"]).redact("not json"), "not json");
+        assert_eq!(redaction(&["$.missing=drop"]).redact(r#"{"a":1}"#), r#"{"a":1}"#);
+    }
+
+    #[test]
+    fn redacts_array_elements_by_index() {
+        let redaction = redaction(&["$.a[0].b=drop", "$.c[1]=drop"]);
+        let redacted = redaction.redact(r#"{"a":[{"b":1,"d":2},{"b":3}],"c":[1,2,3]}"#);
+        assert_eq!(redacted, r#"{"a":[{"d":2},{"b":3}],"c":[1,3]}"#);
+    }
+}
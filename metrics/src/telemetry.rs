@@ -1,31 +1,84 @@
-use std::collections::HashMap;
-use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts};
-use prometheus::{register_int_counter_vec, register_int_counter, register_int_gauge, register_int_gauge_vec, register_histogram_vec};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use prometheus::proto::{self, LabelPair, MetricFamily, MetricType};
+use log::{info, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+use serde_json::Value;
+use crate::histogram::{HistogramConfig, HistogramMetric, NativeHistogramVec};
 
 #[allow(non_snake_case)]
 #[derive(Clone, Debug)]
 pub struct Telemetry {
+    /// Metrics about hasura
+    pub registry: Registry,
+    /// Metrics about the adapter itself, part of `registry` unless they are
+    /// served separately
+    pub self_registry: Registry,
+    /// Labels added to every metric
+    pub common_labels: HashMap<String, String>,
+    separate_self_metrics: bool,
+
     pub ERRORS_TOTAL: IntCounterVec,
 
+    pub ASYNC_ACTIONS: SnapshotVec,
+    pub ASYNC_ACTIONS_OLDEST_PENDING_AGE: SnapshotVec,
+
     pub CRON_TRIGGER_PENDING: IntGaugeVec,
     pub CRON_TRIGGER_PROCESSED: IntGaugeVec,
     pub CRON_TRIGGER_SUCCESSFUL: IntGaugeVec,
     pub CRON_TRIGGER_FAILED: IntGaugeVec,
+    pub CRON_TRIGGER_OLDEST_PENDING_AGE: SnapshotVec,
+    pub CRON_TRIGGER_DELIVERY_LATENCY: SnapshotHistogramVec,
+    pub CRON_TRIGGER_INVOCATIONS: SnapshotVec,
+    pub CRON_TRIGGER_RETRIES: SnapshotVec,
+    pub CRON_TRIGGER_SUCCESSFUL_TOTAL: IntCounterVec,
+    pub CRON_TRIGGER_FAILED_TOTAL: IntCounterVec,
 
     pub EVENT_TRIGGER_PENDING: IntGaugeVec,
     pub EVENT_TRIGGER_PROCESSED: IntGaugeVec,
     pub EVENT_TRIGGER_SUCCESSFUL: IntGaugeVec,
     pub EVENT_TRIGGER_FAILED: IntGaugeVec,
+    pub EVENT_TRIGGER_OLDEST_PENDING_AGE: SnapshotVec,
+    pub EVENT_TRIGGER_DELIVERY_LATENCY: SnapshotHistogramVec,
+    pub EVENT_TRIGGER_INVOCATIONS: SnapshotVec,
+    pub EVENT_TRIGGER_RETRIES: SnapshotVec,
+    pub EVENT_TRIGGER_SUCCESSFUL_TOTAL: IntCounterVec,
+    pub EVENT_TRIGGER_FAILED_TOTAL: IntCounterVec,
 
     pub HEALTH_CHECK: IntGauge,
 
     pub METADATA_CONSISTENCY: IntGauge,
+    pub METADATA_INCONSISTENT_OBJECT: SnapshotVec,
+    pub METADATA_INCONSISTENT_OBJECTS: IntGauge,
+    pub METADATA_SOURCES: SnapshotVec,
+    pub METADATA_TABLES: SnapshotVec,
+    pub METADATA_RELATIONSHIPS: SnapshotVec,
+    pub METADATA_PERMISSIONS: SnapshotVec,
+    pub METADATA_EVENT_TRIGGERS: SnapshotVec,
+    pub METADATA_ACTIONS: SnapshotVec,
+    pub METADATA_REMOTE_SCHEMAS: IntGauge,
+    pub METADATA_CRON_TRIGGERS: IntGauge,
+    pub METADATA_REST_ENDPOINTS: IntGauge,
+    pub METADATA_QUERY_COLLECTIONS: IntGauge,
+    pub METADATA_ALLOWLIST_ENTRIES: IntGauge,
     pub METADATA_VERSION: IntGaugeVec,
 
+    pub REMOTE_SCHEMA_UP: SnapshotVec,
+    pub REMOTE_SCHEMA_PROBE_DURATION: SnapshotVec,
+    pub REMOTE_SCHEMA_LAST_ERROR: SnapshotVec,
+
     pub SCHEDULED_EVENTS_PENDING: IntGauge,
     pub SCHEDULED_EVENTS_PROCESSED: IntGauge,
     pub SCHEDULED_EVENTS_SUCCESSFUL: IntGauge,
     pub SCHEDULED_EVENTS_FAILED: IntGauge,
+    pub SCHEDULED_EVENTS_OLDEST_PENDING_AGE: IntGauge,
+    pub SCHEDULED_EVENTS_DELIVERY_LATENCY: SnapshotHistogramVec,
+    pub SCHEDULED_EVENTS_INVOCATIONS: SnapshotVec,
+    pub SCHEDULED_EVENTS_RETRIES: SnapshotVec,
+    pub SCHEDULED_EVENTS_SUCCESSFUL_TOTAL: IntCounter,
+    pub SCHEDULED_EVENTS_FAILED_TOTAL: IntCounter,
 
     pub ACTIVE_WEBSOCKET: IntGauge,
     pub ACTIVE_WEBSOCKET_OPERATIONS: IntGauge,
@@ -36,266 +89,501 @@ pub struct Telemetry {
 
     pub REQUEST_COUNTER: IntCounterVec,
     pub REQUEST_QUERY_COUNTER: IntCounterVec,
-    pub QUERY_EXECUTION_TIMES: HistogramVec,
+    pub QUERY_EXECUTION_TIMES: HistogramMetric,
+    pub QUERY_EXECUTION_EXEMPLARS: HistogramExemplars,
 
 }
 
-pub enum MetricOption<'a> {
-    IntGaugeVec(&'a IntGaugeVec),
-    IntGauge(&'a IntGauge)
+#[derive(Clone, Debug)]
+pub struct Exemplar {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp: f64,
 }
 
-impl Telemetry {
-    pub fn new(common_labels: HashMap<String, String>, histogram_buckets: Vec<f64>) -> Telemetry {
+/// Holds the latest exemplar per bucket and label set of a histogram, since the
+/// prometheus crate has no notion of exemplars. They are attached to the buckets
+/// when encoding OpenMetrics or protobuf.
+#[derive(Clone, Debug)]
+pub struct HistogramExemplars {
+    pub name: String,
+    pub native: bool,
+    label_names: Vec<String>,
+    buckets: Vec<f64>,
+    exemplars: Arc<Mutex<HashMap<Vec<String>, BucketExemplars>>>,
+}
 
-        let errors_total_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_errors_total"),
-            help : String::from("The total number of errors per collector"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
+/// Exemplars of a single series by bucket index.
+pub type BucketExemplars = HashMap<usize, Exemplar>;
+
+impl HistogramExemplars {
+    fn new(name: &str, label_names: &[&str], histogram: &HistogramMetric, config: &HistogramConfig) -> Self {
+        HistogramExemplars {
+            name: name.to_string(),
+            native: histogram.is_native(),
+            label_names: label_names.iter().map(|v| v.to_string()).collect(),
+            buckets: histogram.buckets(config, name),
+            exemplars: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn observe(&self, label_values: &[&str], value: f64, labels: Vec<(String, String)>) {
+        let bucket = self.buckets.iter().position(|upper_bound| value <= *upper_bound).unwrap_or(self.buckets.len());
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        let exemplar = Exemplar {
+            labels,
+            value,
+            timestamp: crate::exposition::now(),
         };
+        self.exemplars.lock().unwrap().entry(key).or_default().insert(bucket, exemplar);
+    }
 
+    /// Returns the exemplars of the series with the given labels by bucket index,
+    /// where the index one past the last bucket is the `+Inf` bucket. Native
+    /// histograms have no classic buckets, all their exemplars are at index 0.
+    pub fn get(&self, labels: &[LabelPair]) -> BucketExemplars {
+        let key: Vec<String> = self.label_names.iter()
+            .map(|name| labels.iter().find(|l| l.get_name() == name).map_or("".to_string(), |l| l.get_value().to_string()))
+            .collect();
+        self.exemplars.lock().unwrap().get(&key).cloned().unwrap_or_default()
+    }
+}
 
-        let cron_trigger_pending_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_pending_cron_triggers"),
-            help : String::from("Number of pending hasura cron triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let cron_trigger_processed_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_processed_cron_triggers"),
-            help : String::from("Number of processed hasura cron triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let cron_trigger_successful_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_successful_cron_triggers"),
-            help : String::from("Number of successfully processed hasura cron triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let cron_trigger_failed_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_failed_cron_triggers"),
-            help : String::from("Number of failed hasura cron triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
+/// Maps the default `hasura_` metric names to the exposed ones, applying the
+/// configured prefix and renames, and leaves dropped metrics unregistered.
+#[derive(Clone, Debug)]
+pub struct MetricNaming {
+    pub prefix: String,
+    pub renames: HashMap<String, String>,
+    pub dropped: Vec<String>,
+}
 
+impl MetricNaming {
+    /// The name a metric is exposed under, given its default name.
+    pub fn exposed_name(&self, default_name: &str) -> String {
+        match self.renames.get(default_name) {
+            Some(renamed) => renamed.clone(),
+            None => {
+                let base_name = default_name.strip_prefix("hasura_").unwrap_or(default_name);
+                if self.prefix.is_empty() {
+                    base_name.to_string()
+                } else {
+                    format!("{}_{}", self.prefix, base_name)
+                }
+            }
+        }
+    }
 
-        let event_trigger_pending_opts = Opts {
+    fn opts(&self, default_name: &str, help: &str, common_labels: &HashMap<String, String>) -> Opts {
+        Opts {
             namespace: String::from(""),
             subsystem: String::from(""),
-            name : String::from("hasura_pending_event_triggers"),
-            help : String::from("Number of pending hasura event triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let event_trigger_processed_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_processed_event_triggers"),
-            help : String::from("Number of processed hasura event triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let event_trigger_successful_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_successful_event_triggers"),
-            help : String::from("Number of successfully processed hasura event triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let event_trigger_failed_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_failed_event_triggers"),
-            help : String::from("Number of failed hasura event triggers"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
+            name: self.exposed_name(default_name),
+            help: help.to_string(),
+            const_labels: common_labels.clone(),
+            variable_labels: vec![],
+        }
+    }
 
+    /// Registers the metric unless it is dropped, either by its default or its
+    /// exposed name. Dropped metrics are still updated, but never gathered.
+    /// Fails when the exposed name collides with an already registered metric.
+    fn register<T: Collector + Clone + 'static>(&self, registry: &Registry, metric: T) -> prometheus::Result<T> {
+        let name = metric.desc()[0].fq_name.clone();
+        if self.dropped.iter().any(|dropped| *dropped == name || self.exposed_name(dropped) == name) {
+            info!("Dropping metric {}", name);
+        } else {
+            registry.register(Box::new(metric.clone()))
+                .map_err(|e| prometheus::Error::Msg(format!("{}: {}", name, e)))?;
+        }
+        Ok(metric)
+    }
+}
 
-        let health_check_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_healthy"),
-            help : String::from("If 1, Hasura GraphQl server is healthy, 0 otherwise"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
+/// The label values and value of a sample.
+pub type Sample = (Vec<String>, f64);
+
+/// The sample of a JSON value, none if it is not numeric. Strings are parsed, booleans
+/// are 0 or 1, and missing or null label values are empty.
+pub fn json_to_sample<'a>(value: Option<&Value>, labels: impl IntoIterator<Item = Option<&'a Value>>) -> Option<Sample> {
+    let value = match value? {
+        Value::String(s) => s.trim().parse::<f64>().ok()?,
+        Value::Number(n) => n.as_f64()?,
+        Value::Bool(b) => *b as i64 as f64,
+        _ => return None,
+    };
+    let labels = labels.into_iter().map(|label| match label {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    }).collect();
+    Some((labels, value))
+}
 
+/// The samples without duplicate label values, which would be duplicate series. The
+/// first sample of the label values is kept, the others are logged and dropped.
+pub fn unique_samples(samples: impl IntoIterator<Item = Sample>) -> Vec<Sample> {
+    let mut unique = BTreeMap::new();
+    for (labels, value) in samples {
+        match unique.entry(labels) {
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+            Entry::Occupied(entry) => warn!("Dropping the sample {} of the duplicate label values {:?}", value, entry.key()),
+        }
+    }
+    unique.into_iter().collect()
+}
 
-        let metadata_consistency_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_metadata_consistency_status"),
-            help : String::from("If 1, metadata is consistent, 0 otherwise"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let metadata_version_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_metadata_version"),
-            help : String::from("If 1, version is active, 0 otherwise"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
+/// A metric whose samples are replaced as a whole by every collection, so label
+/// sets that are no longer reported disappear instead of keeping their last value.
+#[derive(Clone, Debug)]
+pub struct SnapshotVec {
+    desc: Desc,
+    metric_type: MetricType,
+    label_names: Vec<String>,
+    samples: Arc<Mutex<Vec<Sample>>>,
+}
 
+impl SnapshotVec {
+    pub fn new(opts: Opts, label_names: &[&str], metric_type: MetricType) -> prometheus::Result<Self> {
+        let label_names: Vec<String> = label_names.iter().map(|name| name.to_string()).collect();
+        let desc = Desc::new(opts.fq_name(), opts.help, label_names.clone(), opts.const_labels)?;
+        Ok(SnapshotVec {
+            desc,
+            metric_type,
+            label_names,
+            samples: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
 
-        let scheduled_events_pending_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_pending_one_off_events"),
-            help : String::from("Number of pending Hasura one off scheduled events"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let scheduled_events_processed_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_processed_one_off_events"),
-            help : String::from("Number of processed Hasura one off scheduled events"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let scheduled_events_successful_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_successful_one_off_events"),
-            help : String::from("Number of successful Hasura one off scheduled events"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let scheduled_events_failed_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_failed_one_off_events"),
-            help : String::from("Number of failed Hasura one off scheduled events"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
+    pub fn set(&self, samples: Vec<Sample>) {
+        *self.samples.lock().unwrap() = samples;
+    }
+}
 
+impl Collector for SnapshotVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
 
-        let active_websockets_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_websockets_active"),
-            help : String::from("Number of Hasura web socket connectios"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let active_websockets_operations_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_websockets_operations_active"),
-            help : String::from("Number of Hasura web socket operations like subscriptions"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let websockets_operations_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_websockets_operations"),
-            help : String::from("Counts websocket operation by operation name and error code. On success, error is '', otherwise it's the error code. Unnnamed operations are ''"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
+    fn collect(&self) -> Vec<MetricFamily> {
+        let metrics = self.samples.lock().unwrap().iter().map(|(label_values, value)| {
+            let mut metric = proto::Metric::default();
+            metric.set_label(label_pairs(&self.desc, &self.label_names, label_values).into());
+            match self.metric_type {
+                MetricType::COUNTER => {
+                    let mut counter = proto::Counter::default();
+                    counter.set_value(*value);
+                    metric.set_counter(counter);
+                }
+                _ => {
+                    let mut gauge = proto::Gauge::default();
+                    gauge.set_value(*value);
+                    metric.set_gauge(gauge);
+                }
+            }
+            metric
+        }).collect::<Vec<_>>();
+
+        if metrics.is_empty() {
+            return vec![];
+        }
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(self.metric_type);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
 
+/// The const labels and the named label values, sorted by name.
+fn label_pairs(desc: &Desc, label_names: &[String], label_values: &[String]) -> Vec<LabelPair> {
+    let mut labels = desc.const_label_pairs.clone();
+    for (name, value) in label_names.iter().zip(label_values) {
+        let mut label = LabelPair::default();
+        label.set_name(name.clone());
+        label.set_value(value.clone());
+        labels.push(label);
+    }
+    labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    labels
+}
 
-        let log_lines_counter_total_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_log_lines_counter_total"),
-            help : String::from("Total number of log lines processed"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let log_lines_counter_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_log_lines_counter"),
-            help : String::from("Number of log lines processed"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
+/// The label values, cumulative bucket counts and sum of a histogram series. The
+/// last count is the `+Inf` bucket, the count of the series.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramSample {
+    pub labels: Vec<String>,
+    pub buckets: Vec<u64>,
+    pub sum: f64,
+}
 
+/// A histogram whose series are replaced as a whole by every collection, for
+/// observations which are counted elsewhere, e.g. by a query of the invocation window.
+#[derive(Clone, Debug)]
+pub struct SnapshotHistogramVec {
+    desc: Desc,
+    label_names: Vec<String>,
+    buckets: Vec<f64>,
+    samples: Arc<Mutex<Vec<HistogramSample>>>,
+}
 
-        let request_counter_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_request_counter"),
-            help : String::from("Number of http requests. It provides status the http status code and url the path that was called."),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let request_query_counter_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_request_query_counter"),
-            help : String::from("Number of query requests. On success, error is '', otherwise it's the error code. Unnnamed operations are ''"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let query_execution_seconds_opts = Opts {
-            namespace: String::from(""),
-            subsystem: String::from(""),
-            name : String::from("hasura_query_execution_seconds"),
-            help : String::from("Query execution time. On success, error is '', otherwise it's the error code. Unnnamed operations are ''"),
-            const_labels : common_labels.clone(),
-            variable_labels : vec![]
-        };
-        let query_execution_seconds_histogram_opts = HistogramOpts {
-            common_opts: query_execution_seconds_opts,
-            buckets: histogram_buckets
-        };
+impl SnapshotHistogramVec {
+    pub fn new(opts: Opts, label_names: &[&str], buckets: Vec<f64>) -> prometheus::Result<Self> {
+        let label_names: Vec<String> = label_names.iter().map(|name| name.to_string()).collect();
+        let desc = Desc::new(opts.fq_name(), opts.help, label_names.clone(), opts.const_labels)?;
+        Ok(SnapshotHistogramVec {
+            desc,
+            label_names,
+            buckets,
+            samples: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
 
+    /// The upper bounds of the buckets, without `+Inf`.
+    pub fn buckets(&self) -> &[f64] {
+        &self.buckets
+    }
 
-        Telemetry {
-            ERRORS_TOTAL : register_int_counter_vec!(errors_total_opts,&["collector"]).unwrap(),
+    pub fn set(&self, samples: Vec<HistogramSample>) {
+        *self.samples.lock().unwrap() = samples;
+    }
+}
 
-            CRON_TRIGGER_PENDING: register_int_gauge_vec!(cron_trigger_pending_opts,&["trigger_name"]).unwrap(),
-            CRON_TRIGGER_PROCESSED: register_int_gauge_vec!(cron_trigger_processed_opts,&["trigger_name"]).unwrap(),
-            CRON_TRIGGER_SUCCESSFUL: register_int_gauge_vec!(cron_trigger_successful_opts,&["trigger_name"]).unwrap(),
-            CRON_TRIGGER_FAILED: register_int_gauge_vec!(cron_trigger_failed_opts,&["trigger_name"]).unwrap(),
+impl Collector for SnapshotHistogramVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let metrics = self.samples.lock().unwrap().iter().map(|sample| {
+            let mut histogram = proto::Histogram::default();
+            histogram.set_sample_count(sample.buckets.last().copied().unwrap_or_default());
+            histogram.set_sample_sum(sample.sum);
+            histogram.set_bucket(self.buckets.iter().zip(&sample.buckets).map(|(upper_bound, count)| {
+                let mut bucket = proto::Bucket::default();
+                bucket.set_upper_bound(*upper_bound);
+                bucket.set_cumulative_count(*count);
+                bucket
+            }).collect::<Vec<_>>().into());
+
+            let mut metric = proto::Metric::default();
+            metric.set_label(label_pairs(&self.desc, &self.label_names, &sample.labels).into());
+            metric.set_histogram(histogram);
+            metric
+        }).collect::<Vec<_>>();
+
+        if metrics.is_empty() {
+            return vec![];
+        }
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+pub enum MetricOption<'a> {
+    IntGaugeVec(&'a IntGaugeVec),
+    IntGauge(&'a IntGauge)
+}
+
+impl Telemetry {
+    pub fn new(common_labels: HashMap<String, String>, naming: MetricNaming, histograms: HistogramConfig, separate_self_metrics: bool) -> prometheus::Result<Telemetry> {
 
-            EVENT_TRIGGER_PENDING: register_int_gauge_vec!(event_trigger_pending_opts,&["trigger_name","database_name"]).unwrap(),
-            EVENT_TRIGGER_PROCESSED: register_int_gauge_vec!(event_trigger_processed_opts,&["trigger_name","database_name"]).unwrap(),
-            EVENT_TRIGGER_SUCCESSFUL: register_int_gauge_vec!(event_trigger_successful_opts,&["trigger_name","database_name"]).unwrap(),
-            EVENT_TRIGGER_FAILED: register_int_gauge_vec!(event_trigger_failed_opts,&["trigger_name","database_name"]).unwrap(),
+        let registry = Registry::new();
+        let self_registry = if separate_self_metrics { Registry::new() } else { registry.clone() };
 
-            HEALTH_CHECK: register_int_gauge!(health_check_opts).unwrap(),
+        let errors_total_opts = naming.opts("hasura_errors_total", "The total number of errors per collector", &common_labels);
 
-            METADATA_CONSISTENCY: register_int_gauge!(metadata_consistency_opts).unwrap(),
-            METADATA_VERSION: register_int_gauge_vec!(metadata_version_opts,&["hasura_version"]).unwrap(),
 
-            SCHEDULED_EVENTS_PENDING: register_int_gauge!(scheduled_events_pending_opts).unwrap(),
-            SCHEDULED_EVENTS_PROCESSED: register_int_gauge!(scheduled_events_processed_opts).unwrap(),
-            SCHEDULED_EVENTS_SUCCESSFUL: register_int_gauge!(scheduled_events_successful_opts).unwrap(),
-            SCHEDULED_EVENTS_FAILED: register_int_gauge!(scheduled_events_failed_opts).unwrap(),
+        let async_actions_opts = naming.opts("hasura_async_actions", "Number of hasura async actions per status", &common_labels);
+        let async_actions_oldest_pending_age_opts = naming.opts("hasura_oldest_pending_async_action_age_seconds", "Age of the oldest unprocessed hasura async action in seconds", &common_labels);
+
+
+        let cron_trigger_pending_opts = naming.opts("hasura_pending_cron_triggers", "Number of pending hasura cron triggers", &common_labels);
+        let cron_trigger_processed_opts = naming.opts("hasura_processed_cron_triggers", "Number of processed hasura cron triggers", &common_labels);
+        let cron_trigger_successful_opts = naming.opts("hasura_successful_cron_triggers", "Number of successfully processed hasura cron triggers", &common_labels);
+        let cron_trigger_failed_opts = naming.opts("hasura_failed_cron_triggers", "Number of failed hasura cron triggers", &common_labels);
+        let cron_trigger_oldest_pending_age_opts = naming.opts("hasura_oldest_pending_cron_trigger_age_seconds", "Age of the oldest overdue hasura cron trigger event in seconds", &common_labels);
+        let cron_trigger_delivery_latency_opts = naming.opts("hasura_cron_trigger_delivery_latency_window_seconds", "Delay from the scheduled time of hasura cron trigger events to their invocations within the invocation window", &common_labels);
+        let cron_trigger_invocations_opts = naming.opts("hasura_cron_trigger_invocations", "Number of hasura cron trigger invocations within the invocation window per webhook response status", &common_labels);
+        let cron_trigger_retries_opts = naming.opts("hasura_cron_trigger_retries", "Number of retries of the hasura cron trigger events invoked within the invocation window", &common_labels);
+        let cron_trigger_successful_total_opts = naming.opts("hasura_successful_cron_triggers_total", "Total number of hasura cron trigger events delivered since the start, counted incrementally", &common_labels);
+        let cron_trigger_failed_total_opts = naming.opts("hasura_failed_cron_triggers_total", "Total number of hasura cron trigger events failed since the start, counted incrementally", &common_labels);
+
+
+        let event_trigger_pending_opts = naming.opts("hasura_pending_event_triggers", "Number of pending hasura event triggers", &common_labels);
+        let event_trigger_processed_opts = naming.opts("hasura_processed_event_triggers", "Number of processed hasura event triggers", &common_labels);
+        let event_trigger_successful_opts = naming.opts("hasura_successful_event_triggers", "Number of successfully processed hasura event triggers", &common_labels);
+        let event_trigger_failed_opts = naming.opts("hasura_failed_event_triggers", "Number of failed hasura event triggers", &common_labels);
+        let event_trigger_oldest_pending_age_opts = naming.opts("hasura_oldest_pending_event_trigger_age_seconds", "Age of the oldest pending hasura event trigger event in seconds", &common_labels);
+        let event_trigger_delivery_latency_opts = naming.opts("hasura_event_trigger_delivery_latency_window_seconds", "Delay from hasura event trigger events to their invocations within the invocation window", &common_labels);
+        let event_trigger_invocations_opts = naming.opts("hasura_event_trigger_invocations", "Number of hasura event trigger invocations within the invocation window per webhook response status", &common_labels);
+        let event_trigger_retries_opts = naming.opts("hasura_event_trigger_retries", "Number of retries of the hasura event trigger events invoked within the invocation window", &common_labels);
+        let event_trigger_successful_total_opts = naming.opts("hasura_successful_event_triggers_total", "Total number of hasura event trigger events delivered since the start, counted incrementally", &common_labels);
+        let event_trigger_failed_total_opts = naming.opts("hasura_failed_event_triggers_total", "Total number of hasura event trigger events failed since the start, counted incrementally", &common_labels);
+
+
+        let health_check_opts = naming.opts("hasura_healthy", "If 1, Hasura GraphQl server is healthy, 0 otherwise", &common_labels);
+
+
+        let metadata_consistency_opts = naming.opts("hasura_metadata_consistency_status", "If 1, metadata is consistent, 0 otherwise", &common_labels);
+        let metadata_inconsistent_object_opts = naming.opts("hasura_metadata_inconsistent_object", "1 for every inconsistent metadata object, labeled with its type, name and reason category", &common_labels);
+        let metadata_inconsistent_objects_opts = naming.opts("hasura_metadata_inconsistent_objects", "Number of inconsistent metadata objects", &common_labels);
+        let metadata_sources_opts = naming.opts("hasura_metadata_sources", "Number of sources in the hasura metadata per kind", &common_labels);
+        let metadata_tables_opts = naming.opts("hasura_metadata_tables", "Number of tracked tables in the hasura metadata per source", &common_labels);
+        let metadata_relationships_opts = naming.opts("hasura_metadata_relationships", "Number of relationships in the hasura metadata per source and type", &common_labels);
+        let metadata_permissions_opts = naming.opts("hasura_metadata_permissions", "Number of table permissions in the hasura metadata per source, role and action", &common_labels);
+        let metadata_event_triggers_opts = naming.opts("hasura_metadata_event_triggers", "Number of event triggers in the hasura metadata per source", &common_labels);
+        let metadata_actions_opts = naming.opts("hasura_metadata_actions", "Number of actions in the hasura metadata per type and kind", &common_labels);
+        let metadata_remote_schemas_opts = naming.opts("hasura_metadata_remote_schemas", "Number of remote schemas in the hasura metadata", &common_labels);
+        let metadata_cron_triggers_opts = naming.opts("hasura_metadata_cron_triggers", "Number of cron triggers in the hasura metadata", &common_labels);
+        let metadata_rest_endpoints_opts = naming.opts("hasura_metadata_rest_endpoints", "Number of REST endpoints in the hasura metadata", &common_labels);
+        let metadata_query_collections_opts = naming.opts("hasura_metadata_query_collections", "Number of query collections in the hasura metadata", &common_labels);
+        let metadata_allowlist_entries_opts = naming.opts("hasura_metadata_allowlist_entries", "Number of allow-list entries in the hasura metadata", &common_labels);
+        let metadata_version_opts = naming.opts("hasura_metadata_version", "If 1, version is active, 0 otherwise", &common_labels);
+
+        let remote_schema_up_opts = naming.opts("hasura_remote_schema_up", "If 1, the hasura remote schema answered the ping query, 0 otherwise", &common_labels);
+        let remote_schema_probe_duration_opts = naming.opts("hasura_remote_schema_probe_duration_seconds", "Duration of the last successful ping query of the hasura remote schema in seconds", &common_labels);
+        let remote_schema_last_error_opts = naming.opts("hasura_remote_schema_last_error_timestamp_seconds", "Unix time of the last failed ping query of the hasura remote schema, labeled with the error category", &common_labels);
+
+
+        let scheduled_events_pending_opts = naming.opts("hasura_pending_one_off_events", "Number of pending Hasura one off scheduled events", &common_labels);
+        let scheduled_events_processed_opts = naming.opts("hasura_processed_one_off_events", "Number of processed Hasura one off scheduled events", &common_labels);
+        let scheduled_events_successful_opts = naming.opts("hasura_successful_one_off_events", "Number of successful Hasura one off scheduled events", &common_labels);
+        let scheduled_events_failed_opts = naming.opts("hasura_failed_one_off_events", "Number of failed Hasura one off scheduled events", &common_labels);
+        let scheduled_events_oldest_pending_age_opts = naming.opts("hasura_oldest_pending_one_off_event_age_seconds", "Age of the oldest overdue Hasura one off scheduled event in seconds, 0 if none is overdue", &common_labels);
+        let scheduled_events_delivery_latency_opts = naming.opts("hasura_one_off_event_delivery_latency_window_seconds", "Delay from the scheduled time of Hasura one off scheduled events to their invocations within the invocation window", &common_labels);
+        let scheduled_events_invocations_opts = naming.opts("hasura_one_off_event_invocations", "Number of Hasura one off scheduled event invocations within the invocation window per webhook response status", &common_labels);
+        let scheduled_events_retries_opts = naming.opts("hasura_one_off_event_retries", "Number of retries of the Hasura one off scheduled events invoked within the invocation window", &common_labels);
+        let scheduled_events_successful_total_opts = naming.opts("hasura_successful_one_off_events_total", "Total number of Hasura one off scheduled events delivered since the start, counted incrementally", &common_labels);
+        let scheduled_events_failed_total_opts = naming.opts("hasura_failed_one_off_events_total", "Total number of Hasura one off scheduled events failed since the start, counted incrementally", &common_labels);
+
+
+        let active_websockets_opts = naming.opts("hasura_websockets_active", "Number of Hasura web socket connectios", &common_labels);
+        let active_websockets_operations_opts = naming.opts("hasura_websockets_operations_active", "Number of Hasura web socket operations like subscriptions", &common_labels);
+        let websockets_operations_opts = naming.opts("hasura_websockets_operations", "Counts websocket operation by operation name and error code. On success, error is '', otherwise it's the error code. Unnnamed operations are ''", &common_labels);
+
+
+        let log_lines_counter_total_opts = naming.opts("hasura_log_lines_counter_total", "Total number of log lines processed", &common_labels);
+        let log_lines_counter_opts = naming.opts("hasura_log_lines_counter", "Number of log lines processed", &common_labels);
+
+
+        let request_counter_opts = naming.opts("hasura_request_counter", "Number of http requests. It provides status the http status code and url the path that was called.", &common_labels);
+        let request_query_counter_opts = naming.opts("hasura_request_query_counter", "Number of query requests. On success, error is '', otherwise it's the error code. Unnnamed operations are ''", &common_labels);
+        let query_execution_seconds_opts = naming.opts("hasura_query_execution_seconds", "Query execution time. On success, error is '', otherwise it's the error code. Unnnamed operations are ''", &common_labels);
+        let query_execution_times = naming.register(&registry, HistogramMetric::new(query_execution_seconds_opts.clone(), &["operation", "error"], &histograms)?)?;
+        let query_execution_exemplars = HistogramExemplars::new(&query_execution_seconds_opts.fq_name(), &["operation", "error"], &query_execution_times, &histograms);
+
+
+        Ok(Telemetry {
+            ERRORS_TOTAL : naming.register(&self_registry, IntCounterVec::new(errors_total_opts,&["collector"])?)?,
+
+            ASYNC_ACTIONS: naming.register(&registry, SnapshotVec::new(async_actions_opts,&["action_name","status"], MetricType::GAUGE)?)?,
+            ASYNC_ACTIONS_OLDEST_PENDING_AGE: naming.register(&registry, SnapshotVec::new(async_actions_oldest_pending_age_opts,&["action_name"], MetricType::GAUGE)?)?,
+
+            CRON_TRIGGER_PENDING: naming.register(&registry, IntGaugeVec::new(cron_trigger_pending_opts,&["trigger_name"])?)?,
+            CRON_TRIGGER_PROCESSED: naming.register(&registry, IntGaugeVec::new(cron_trigger_processed_opts,&["trigger_name"])?)?,
+            CRON_TRIGGER_SUCCESSFUL: naming.register(&registry, IntGaugeVec::new(cron_trigger_successful_opts,&["trigger_name"])?)?,
+            CRON_TRIGGER_FAILED: naming.register(&registry, IntGaugeVec::new(cron_trigger_failed_opts,&["trigger_name"])?)?,
+            CRON_TRIGGER_OLDEST_PENDING_AGE: naming.register(&registry, SnapshotVec::new(cron_trigger_oldest_pending_age_opts,&["trigger_name"], MetricType::GAUGE)?)?,
+            CRON_TRIGGER_DELIVERY_LATENCY: naming.register(&registry, SnapshotHistogramVec::new(cron_trigger_delivery_latency_opts.clone(),&["trigger_name"], histograms.latency_buckets_for(&cron_trigger_delivery_latency_opts.fq_name()))?)?,
+            CRON_TRIGGER_INVOCATIONS: naming.register(&registry, SnapshotVec::new(cron_trigger_invocations_opts,&["trigger_name","status"], MetricType::GAUGE)?)?,
+            CRON_TRIGGER_RETRIES: naming.register(&registry, SnapshotVec::new(cron_trigger_retries_opts,&["trigger_name"], MetricType::GAUGE)?)?,
+            CRON_TRIGGER_SUCCESSFUL_TOTAL: naming.register(&registry, IntCounterVec::new(cron_trigger_successful_total_opts,&["trigger_name"])?)?,
+            CRON_TRIGGER_FAILED_TOTAL: naming.register(&registry, IntCounterVec::new(cron_trigger_failed_total_opts,&["trigger_name"])?)?,
+
+            EVENT_TRIGGER_PENDING: naming.register(&registry, IntGaugeVec::new(event_trigger_pending_opts,&["trigger_name","database_name"])?)?,
+            EVENT_TRIGGER_PROCESSED: naming.register(&registry, IntGaugeVec::new(event_trigger_processed_opts,&["trigger_name","database_name"])?)?,
+            EVENT_TRIGGER_SUCCESSFUL: naming.register(&registry, IntGaugeVec::new(event_trigger_successful_opts,&["trigger_name","database_name"])?)?,
+            EVENT_TRIGGER_FAILED: naming.register(&registry, IntGaugeVec::new(event_trigger_failed_opts,&["trigger_name","database_name"])?)?,
+            EVENT_TRIGGER_OLDEST_PENDING_AGE: naming.register(&registry, SnapshotVec::new(event_trigger_oldest_pending_age_opts,&["trigger_name","database_name"], MetricType::GAUGE)?)?,
+            EVENT_TRIGGER_DELIVERY_LATENCY: naming.register(&registry, SnapshotHistogramVec::new(event_trigger_delivery_latency_opts.clone(),&["trigger_name","database_name"], histograms.latency_buckets_for(&event_trigger_delivery_latency_opts.fq_name()))?)?,
+            EVENT_TRIGGER_INVOCATIONS: naming.register(&registry, SnapshotVec::new(event_trigger_invocations_opts,&["trigger_name","status","database_name"], MetricType::GAUGE)?)?,
+            EVENT_TRIGGER_RETRIES: naming.register(&registry, SnapshotVec::new(event_trigger_retries_opts,&["trigger_name","database_name"], MetricType::GAUGE)?)?,
+            EVENT_TRIGGER_SUCCESSFUL_TOTAL: naming.register(&registry, IntCounterVec::new(event_trigger_successful_total_opts,&["trigger_name","database_name"])?)?,
+            EVENT_TRIGGER_FAILED_TOTAL: naming.register(&registry, IntCounterVec::new(event_trigger_failed_total_opts,&["trigger_name","database_name"])?)?,
+
+            HEALTH_CHECK: naming.register(&registry, IntGauge::with_opts(health_check_opts)?)?,
+
+            METADATA_CONSISTENCY: naming.register(&registry, IntGauge::with_opts(metadata_consistency_opts)?)?,
+            METADATA_INCONSISTENT_OBJECT: naming.register(&registry, SnapshotVec::new(metadata_inconsistent_object_opts,&["type","name","reason"], MetricType::GAUGE)?)?,
+            METADATA_INCONSISTENT_OBJECTS: naming.register(&registry, IntGauge::with_opts(metadata_inconsistent_objects_opts)?)?,
+            METADATA_SOURCES: naming.register(&registry, SnapshotVec::new(metadata_sources_opts,&["kind"], MetricType::GAUGE)?)?,
+            METADATA_TABLES: naming.register(&registry, SnapshotVec::new(metadata_tables_opts,&["database_name"], MetricType::GAUGE)?)?,
+            METADATA_RELATIONSHIPS: naming.register(&registry, SnapshotVec::new(metadata_relationships_opts,&["database_name","type"], MetricType::GAUGE)?)?,
+            METADATA_PERMISSIONS: naming.register(&registry, SnapshotVec::new(metadata_permissions_opts,&["database_name","role","action"], MetricType::GAUGE)?)?,
+            METADATA_EVENT_TRIGGERS: naming.register(&registry, SnapshotVec::new(metadata_event_triggers_opts,&["database_name"], MetricType::GAUGE)?)?,
+            METADATA_ACTIONS: naming.register(&registry, SnapshotVec::new(metadata_actions_opts,&["type","kind"], MetricType::GAUGE)?)?,
+            METADATA_REMOTE_SCHEMAS: naming.register(&registry, IntGauge::with_opts(metadata_remote_schemas_opts)?)?,
+            METADATA_CRON_TRIGGERS: naming.register(&registry, IntGauge::with_opts(metadata_cron_triggers_opts)?)?,
+            METADATA_REST_ENDPOINTS: naming.register(&registry, IntGauge::with_opts(metadata_rest_endpoints_opts)?)?,
+            METADATA_QUERY_COLLECTIONS: naming.register(&registry, IntGauge::with_opts(metadata_query_collections_opts)?)?,
+            METADATA_ALLOWLIST_ENTRIES: naming.register(&registry, IntGauge::with_opts(metadata_allowlist_entries_opts)?)?,
+            METADATA_VERSION: naming.register(&registry, IntGaugeVec::new(metadata_version_opts,&["hasura_version"])?)?,
+
+            REMOTE_SCHEMA_UP: naming.register(&registry, SnapshotVec::new(remote_schema_up_opts,&["remote_schema"], MetricType::GAUGE)?)?,
+            REMOTE_SCHEMA_PROBE_DURATION: naming.register(&registry, SnapshotVec::new(remote_schema_probe_duration_opts,&["remote_schema"], MetricType::GAUGE)?)?,
+            REMOTE_SCHEMA_LAST_ERROR: naming.register(&registry, SnapshotVec::new(remote_schema_last_error_opts,&["remote_schema","error"], MetricType::GAUGE)?)?,
+
+            SCHEDULED_EVENTS_PENDING: naming.register(&registry, IntGauge::with_opts(scheduled_events_pending_opts)?)?,
+            SCHEDULED_EVENTS_PROCESSED: naming.register(&registry, IntGauge::with_opts(scheduled_events_processed_opts)?)?,
+            SCHEDULED_EVENTS_SUCCESSFUL: naming.register(&registry, IntGauge::with_opts(scheduled_events_successful_opts)?)?,
+            SCHEDULED_EVENTS_FAILED: naming.register(&registry, IntGauge::with_opts(scheduled_events_failed_opts)?)?,
+            SCHEDULED_EVENTS_OLDEST_PENDING_AGE: naming.register(&registry, IntGauge::with_opts(scheduled_events_oldest_pending_age_opts)?)?,
+            SCHEDULED_EVENTS_DELIVERY_LATENCY: naming.register(&registry, SnapshotHistogramVec::new(scheduled_events_delivery_latency_opts.clone(),&[], histograms.latency_buckets_for(&scheduled_events_delivery_latency_opts.fq_name()))?)?,
+            SCHEDULED_EVENTS_INVOCATIONS: naming.register(&registry, SnapshotVec::new(scheduled_events_invocations_opts,&["status"], MetricType::GAUGE)?)?,
+            SCHEDULED_EVENTS_RETRIES: naming.register(&registry, SnapshotVec::new(scheduled_events_retries_opts,&[], MetricType::GAUGE)?)?,
+            SCHEDULED_EVENTS_SUCCESSFUL_TOTAL: naming.register(&registry, IntCounter::with_opts(scheduled_events_successful_total_opts)?)?,
+            SCHEDULED_EVENTS_FAILED_TOTAL: naming.register(&registry, IntCounter::with_opts(scheduled_events_failed_total_opts)?)?,
+
+            ACTIVE_WEBSOCKET: naming.register(&registry, IntGauge::with_opts(active_websockets_opts)?)?,
+            ACTIVE_WEBSOCKET_OPERATIONS: naming.register(&registry, IntGauge::with_opts(active_websockets_operations_opts)?)?,
+            WEBSOCKET_OPERATIONS: naming.register(&registry, IntCounterVec::new(websockets_operations_opts,&["operation", "error"])?)?,
+
+            LOG_LINES_COUNTER_TOTAL: naming.register(&self_registry, IntCounter::with_opts(log_lines_counter_total_opts)?)?,
+            LOG_LINES_COUNTER: naming.register(&self_registry, IntCounterVec::new(log_lines_counter_opts,&["logtype"])?)?,
+
+            REQUEST_COUNTER: naming.register(&registry, IntCounterVec::new(request_counter_opts,&["url", "status"])?)?,
+            REQUEST_QUERY_COUNTER: naming.register(&registry, IntCounterVec::new(request_query_counter_opts,&["operation", "error"])?)?,
+            QUERY_EXECUTION_TIMES: query_execution_times,
+            QUERY_EXECUTION_EXEMPLARS: query_execution_exemplars,
+
+            registry,
+            self_registry,
+            common_labels,
+            separate_self_metrics,
+        })
+
+    }
 
-            ACTIVE_WEBSOCKET: register_int_gauge!(active_websockets_opts).unwrap(),
-            ACTIVE_WEBSOCKET_OPERATIONS: register_int_gauge!(active_websockets_operations_opts).unwrap(),
-            WEBSOCKET_OPERATIONS: register_int_counter_vec!(websockets_operations_opts,&["operation", "error"]).unwrap(),
+    /// All exemplar stores, to be attached to the histograms when encoding.
+    pub fn exemplars(&self) -> Vec<&HistogramExemplars> {
+        vec![&self.QUERY_EXECUTION_EXEMPLARS]
+    }
 
-            LOG_LINES_COUNTER_TOTAL: register_int_counter!(log_lines_counter_total_opts).unwrap(),
-            LOG_LINES_COUNTER: register_int_counter_vec!(log_lines_counter_opts,&["logtype"]).unwrap(),
+    /// All native histograms, their buckets are not part of the gathered families.
+    pub fn native_histograms(&self) -> Vec<&NativeHistogramVec> {
+        [&self.QUERY_EXECUTION_TIMES].into_iter()
+            .filter_map(|h| match h {
+                HistogramMetric::Native(native) => Some(native),
+                HistogramMetric::Classic(_) => None,
+            })
+            .collect()
+    }
 
-            REQUEST_COUNTER: register_int_counter_vec!(request_counter_opts,&["url", "status"]).unwrap(),
-            REQUEST_QUERY_COUNTER: register_int_counter_vec!(request_query_counter_opts,&["operation", "error"]).unwrap(),
-            QUERY_EXECUTION_TIMES: register_histogram_vec!(query_execution_seconds_histogram_opts,&["operation", "error"]).unwrap()
+    /// Gathers the hasura metrics together with the self-metrics, for outputs
+    /// that push everything to a single destination.
+    pub fn gather_all(&self) -> Vec<MetricFamily> {
+        let mut families = self.registry.gather();
+        if self.separate_self_metrics {
+            families.extend(self.self_registry.gather());
         }
-
+        families
     }
 }
\ No newline at end of file